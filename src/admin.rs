@@ -0,0 +1,212 @@
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Body, Incoming};
+use hyper::header::AUTHORIZATION;
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use tracing::{info, warn};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::ratelimit::RateLimit;
+
+/// Handle for swapping the log filter while running.
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+const MAX_ADMIN_BODY: usize = 4096;
+
+#[derive(Deserialize)]
+struct LogLevelRequest {
+    filter: String,
+}
+
+#[derive(Serialize)]
+struct LogLevelResponse {
+    filter: String,
+    previous: String,
+}
+
+/// Handle a request on the admin listener.
+///
+/// Routes:
+/// - `GET /history/{ip}` — recent requests from an IP (needs `rate_limit.history`)
+/// - `POST /loglevel` — replace the log filter, e.g. `{"filter": "wardent=debug"}`.
+///   The previous filter is returned so it can be restored the same way.
+///
+/// With `admin.token` set, every route needs
+/// `Authorization: Bearer <token>` and answers 401 without it.
+pub async fn handle(
+    req: Request<Incoming>,
+    rate_limiter: &RateLimit,
+    log_filter: &LogFilterHandle,
+    token: Option<&str>,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    let path = req.uri().path();
+
+    if !authorized(req.headers(), token) {
+        warn!(path = path, "Unauthorized admin request");
+        return Ok(status_response(StatusCode::UNAUTHORIZED));
+    }
+
+    if path == "/loglevel" {
+        if req.method() != Method::POST {
+            return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED));
+        }
+        return Ok(set_log_level(req, log_filter).await);
+    }
+
+    if let Some(ip) = path.strip_prefix("/history/") {
+        if req.method() != Method::GET {
+            return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED));
+        }
+        return Ok(history(ip, rate_limiter));
+    }
+
+    Ok(status_response(StatusCode::NOT_FOUND))
+}
+
+/// Compares digests rather than the tokens themselves, so the time taken
+/// says nothing about how much of the token was right.
+fn authorized(headers: &HeaderMap, token: Option<&str>) -> bool {
+    let token = match token {
+        Some(token) => token,
+        None => return true,
+    };
+    let given = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    Sha256::digest(given.as_bytes()) == Sha256::digest(token.as_bytes())
+}
+
+fn history(ip: &str, rate_limiter: &RateLimit) -> Response<Full<Bytes>> {
+    if !rate_limiter.history_enabled() {
+        return status_response(StatusCode::NOT_FOUND);
+    }
+
+    let ip: IpAddr = match ip.parse() {
+        Ok(ip) => ip,
+        Err(_) => return status_response(StatusCode::BAD_REQUEST),
+    };
+
+    let entries = rate_limiter.history_for(ip);
+    info!(ip = %ip, entries = entries.len(), "Admin history lookup");
+
+    json_response(StatusCode::OK, &entries)
+}
+
+async fn set_log_level<B>(req: Request<B>, log_filter: &LogFilterHandle) -> Response<Full<Bytes>>
+where
+    B: Body<Data = Bytes>,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let body = match Limited::new(req.into_body(), MAX_ADMIN_BODY).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return status_response(StatusCode::PAYLOAD_TOO_LARGE),
+    };
+
+    let requested: LogLevelRequest = match serde_json::from_slice(&body) {
+        Ok(requested) => requested,
+        Err(_) => return status_response(StatusCode::BAD_REQUEST),
+    };
+
+    let filter = match EnvFilter::try_new(&requested.filter) {
+        Ok(filter) => filter,
+        Err(e) => {
+            warn!(filter = %requested.filter, error = %e, "Rejected invalid log filter");
+            return status_response(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    let previous = log_filter
+        .with_current(|current| current.to_string())
+        .unwrap_or_default();
+    if let Err(e) = log_filter.reload(filter) {
+        warn!(error = %e, "Failed to reload log filter");
+        return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    info!(previous = %previous, filter = %requested.filter, "Log filter changed");
+    json_response(
+        StatusCode::OK,
+        &LogLevelResponse { filter: requested.filter, previous },
+    )
+}
+
+pub fn timeout_response() -> Response<Full<Bytes>> {
+    status_response(StatusCode::SERVICE_UNAVAILABLE)
+}
+
+fn json_response<T: serde::Serialize>(status: StatusCode, value: &T) -> Response<Full<Bytes>> {
+    let body = serde_json::to_vec(value).unwrap_or_default();
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len())
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Length", "0")
+        .body(Full::new(Bytes::new()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bearer(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn token_required_when_configured() {
+        assert!(authorized(&bearer("Bearer s3cret"), Some("s3cret")));
+        assert!(!authorized(&bearer("Bearer wrong"), Some("s3cret")));
+        assert!(!authorized(&bearer("s3cret"), Some("s3cret")));
+        assert!(!authorized(&HeaderMap::new(), Some("s3cret")));
+    }
+
+    #[test]
+    fn no_token_configured_allows_all() {
+        assert!(authorized(&HeaderMap::new(), None));
+    }
+
+    #[tokio::test]
+    async fn log_level_is_swapped_through_the_reload_handle() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let (layer, handle) = reload::Layer::new(EnvFilter::new("wardent=info"));
+        let _default = tracing::subscriber::set_default(Registry::default().with(layer));
+        assert!(!tracing::enabled!(target: "wardent::proxy", tracing::Level::DEBUG));
+
+        let req = Request::post("/loglevel")
+            .body(Full::new(Bytes::from_static(br#"{"filter": "wardent=debug"}"#)))
+            .unwrap();
+        let response = set_log_level(req, &handle).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["previous"], "wardent=info");
+
+        assert!(tracing::enabled!(target: "wardent::proxy", tracing::Level::DEBUG));
+        assert!(!tracing::enabled!(target: "hyper", tracing::Level::DEBUG));
+    }
+
+    #[tokio::test]
+    async fn invalid_log_filter_is_rejected() {
+        let (_, handle) = reload::Layer::<_, Registry>::new(EnvFilter::new("wardent=info"));
+        let req = Request::post("/loglevel")
+            .body(Full::new(Bytes::from_static(br#"{"filter": "wardent=loud"}"#)))
+            .unwrap();
+        assert_eq!(set_log_level(req, &handle).await.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,229 @@
+use bytes::Bytes;
+use futures_util::future::join_all;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{HeaderValue, ACCEPT_ENCODING};
+use hyper::{Method, Request, Response, StatusCode};
+use serde_json::{json, Map, Value};
+use tracing::{info, instrument, warn};
+
+use crate::config::{AggregateRoute, Config, Timeouts};
+use crate::proxy::{build_upstream_request, send_upstream, UpstreamError};
+
+/// Answer an `[[aggregate]]` route by sending each configured sub-request
+/// upstream concurrently and merging the JSON bodies under their keys.
+///
+/// The client's headers and query string are passed to every sub-request
+/// (a sub-request path with its own query keeps it), except
+/// Accept-Encoding: the bodies are parsed here, so they are always
+/// requested uncompressed. A failed sub-request
+/// is reported as `{"error": ..., "status": ...}` under its key, unless
+/// `fail_on_error` is set, in which case the whole response is a 502.
+#[instrument(skip_all, fields(path = %route.path))]
+pub async fn handle<B>(
+    req: Request<B>,
+    route: &AggregateRoute,
+    config: &Config,
+    client_ip: &str,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    if req.method() != Method::GET {
+        return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    let timeouts = config.timeouts(Method::GET.as_str(), &route.path, Some(route));
+    let query = req.uri().query().map(str::to_string);
+    let mut headers = req.headers().clone();
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+
+    let calls = route.request.iter().map(|sub| {
+        let path_and_query = match &query {
+            Some(q) if !sub.path.contains('?') => format!("{}?{}", sub.path, q),
+            _ => sub.path.clone(),
+        };
+        let outgoing = build_upstream_request(
+            Method::GET,
+            &path_and_query,
+            &headers,
+            client_ip,
+            config,
+            Bytes::new(),
+        );
+
+        async move {
+            let result = match outgoing {
+                Some(outgoing) => fetch_json(outgoing, config, &timeouts).await,
+                None => Err(SubError::new("invalid upstream uri", None)),
+            };
+            (sub.key.as_str(), result)
+        }
+    });
+
+    let results = join_all(calls).await;
+
+    let mut merged = Map::new();
+    let mut failures = 0;
+    for (key, result) in results {
+        match result {
+            Ok(value) => {
+                merged.insert(key.to_string(), value);
+            }
+            Err(e) => {
+                failures += 1;
+                warn!(key = key, error = e.message, status = ?e.status, "Aggregate sub-request failed");
+                merged.insert(key.to_string(), json!({ "error": e.message, "status": e.status }));
+            }
+        }
+    }
+
+    if failures > 0 && route.fail_on_error {
+        return Ok(status_response(StatusCode::BAD_GATEWAY));
+    }
+
+    info!(parts = route.request.len(), failures = failures, "Aggregated response");
+
+    let body = serde_json::to_vec(&Value::Object(merged)).unwrap_or_default();
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len())
+        .body(Full::new(Bytes::from(body)))
+        .unwrap())
+}
+
+struct SubError {
+    message: &'static str,
+    status: Option<u16>,
+}
+
+impl SubError {
+    fn new(message: &'static str, status: Option<u16>) -> Self {
+        Self { message, status }
+    }
+}
+
+async fn fetch_json(
+    req: Request<Full<Bytes>>,
+    config: &Config,
+    timeouts: &Timeouts,
+) -> Result<Value, SubError> {
+    let response = match send_upstream(req, config, timeouts).await {
+        Ok(response) => response,
+        Err(UpstreamError::Timeout) => return Err(SubError::new("upstream timeout", None)),
+        Err(UpstreamError::Failed(_)) => return Err(SubError::new("upstream request failed", None)),
+    };
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(SubError::new("upstream error status", Some(status.as_u16())));
+    }
+
+    let body = match response.into_body().collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(_) => return Err(SubError::new("upstream request failed", None)),
+    };
+
+    serde_json::from_slice(&body)
+        .map_err(|_| SubError::new("invalid json", Some(status.as_u16())))
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Length", "0")
+        .body(Full::new(Bytes::new()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use std::convert::Infallible;
+
+    /// Upstream answering every path with what it was sent, except
+    /// `/missing`, which is a 404.
+    async fn echo_upstream() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    let accept_encoding = req
+                        .headers()
+                        .get(ACCEPT_ENCODING)
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_string);
+                    let (status, body) = match req.uri().path() {
+                        "/missing" => (StatusCode::NOT_FOUND, json!({})),
+                        path => (
+                            StatusCode::OK,
+                            json!({
+                                "path": path,
+                                "query": req.uri().query(),
+                                "accept_encoding": accept_encoding,
+                            }),
+                        ),
+                    };
+                    let response = Response::builder()
+                        .status(status)
+                        .body(Full::new(Bytes::from(body.to_string())))
+                        .unwrap();
+                    Ok::<_, Infallible>(response)
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    fn aggregate_config(upstream: &str, fail_on_error: bool) -> Config {
+        test_config(&format!(
+            r#"
+            proxy.upstream = "{upstream}"
+
+            [[aggregate]]
+            path = "/dashboard"
+            fail_on_error = {fail_on_error}
+            request = [
+                {{ key = "user", path = "/api/user" }},
+                {{ key = "orders", path = "/api/orders?status=open" }},
+                {{ key = "gone", path = "/missing" }},
+            ]
+            "#
+        ))
+    }
+
+    async fn get_dashboard(config: &Config) -> Response<Full<Bytes>> {
+        let req = Request::get("/dashboard?page=2")
+            .header(ACCEPT_ENCODING, "gzip, br")
+            .body(())
+            .unwrap();
+        handle(req, &config.aggregate[0], config, "203.0.113.7").await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn fans_out_and_merges_under_keys() {
+        let config = aggregate_config(&echo_upstream().await, false);
+
+        let response = get_dashboard(&config).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let merged: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(merged["user"]["path"], "/api/user");
+        assert_eq!(merged["user"]["query"], "page=2");
+        assert_eq!(merged["user"]["accept_encoding"], "identity");
+        assert_eq!(merged["orders"]["query"], "status=open");
+        assert_eq!(merged["gone"], json!({ "error": "upstream error status", "status": 404 }));
+    }
+
+    #[tokio::test]
+    async fn fail_on_error_answers_bad_gateway() {
+        let config = aggregate_config(&echo_upstream().await, true);
+        assert_eq!(get_dashboard(&config).await.status(), StatusCode::BAD_GATEWAY);
+    }
+}
@@ -0,0 +1,221 @@
+use governor::clock::{Clock, DefaultClock};
+use governor::middleware::NoOpMiddleware;
+use governor::state::{InMemoryState, NotKeyed};
+use governor::{Quota, RateLimiter};
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::{RateLimitAlgorithm, RateLimitRule};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// A single client's limiter for one per-path rule. The clock is only
+/// swapped out in tests.
+pub enum PathLimiter<C: Clock = DefaultClock> {
+    TokenBucket(RateLimiter<NotKeyed, InMemoryState, C, NoOpMiddleware<C::Instant>>),
+    FixedWindow(Mutex<FixedWindow>),
+    SlidingWindow(Mutex<SlidingWindow>),
+}
+
+impl PathLimiter {
+    pub fn new(rule: &RateLimitRule) -> Self {
+        Self::with_clock(rule, DefaultClock::default())
+    }
+}
+
+impl<C: Clock> PathLimiter<C> {
+    fn with_clock(rule: &RateLimitRule, clock: C) -> Self {
+        let rpm = NonZeroU32::new(rule.requests_per_minute)
+            .expect("rate_limit.rule requests_per_minute must be > 0");
+
+        match rule.algorithm {
+            RateLimitAlgorithm::TokenBucket => {
+                let burst = rule
+                    .burst_size
+                    .and_then(NonZeroU32::new)
+                    .unwrap_or(rpm);
+                let quota = Quota::per_minute(rpm).allow_burst(burst);
+                PathLimiter::TokenBucket(RateLimiter::direct_with_clock(quota, clock))
+            }
+            RateLimitAlgorithm::FixedWindow => {
+                PathLimiter::FixedWindow(Mutex::new(FixedWindow::new(rpm.get())))
+            }
+            RateLimitAlgorithm::SlidingWindow => {
+                PathLimiter::SlidingWindow(Mutex::new(SlidingWindow::new(rpm.get())))
+            }
+        }
+    }
+
+    /// Returns true if the request is allowed.
+    pub fn check(&self) -> bool {
+        self.check_at(Instant::now())
+    }
+
+    /// `now` drives the window algorithms; the token bucket reads its
+    /// own clock.
+    fn check_at(&self, now: Instant) -> bool {
+        match self {
+            PathLimiter::TokenBucket(limiter) => limiter.check().is_ok(),
+            PathLimiter::FixedWindow(window) => window.lock().unwrap().check(now),
+            PathLimiter::SlidingWindow(window) => window.lock().unwrap().check(now),
+        }
+    }
+}
+
+pub struct FixedWindow {
+    limit: u32,
+    window_start: Option<Instant>,
+    count: u32,
+}
+
+impl FixedWindow {
+    pub fn new(limit: u32) -> Self {
+        Self { limit, window_start: None, count: 0 }
+    }
+
+    pub fn check(&mut self, now: Instant) -> bool {
+        let start = *self.window_start.get_or_insert(now);
+        if now.duration_since(start) >= WINDOW {
+            self.window_start = Some(now);
+            self.count = 0;
+        }
+
+        if self.count < self.limit {
+            self.count += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub struct SlidingWindow {
+    limit: u32,
+    window_start: Option<Instant>,
+    previous: u32,
+    current: u32,
+}
+
+impl SlidingWindow {
+    pub fn new(limit: u32) -> Self {
+        Self { limit, window_start: None, previous: 0, current: 0 }
+    }
+
+    pub fn check(&mut self, now: Instant) -> bool {
+        let start = *self.window_start.get_or_insert(now);
+        let elapsed = now.duration_since(start);
+
+        if elapsed >= WINDOW * 2 {
+            self.window_start = Some(now);
+            self.previous = 0;
+            self.current = 0;
+        } else if elapsed >= WINDOW {
+            self.window_start = Some(start + WINDOW);
+            self.previous = self.current;
+            self.current = 0;
+        }
+
+        let into_window = now.duration_since(self.window_start.unwrap_or(now));
+        let overlap = 1.0 - into_window.as_secs_f64() / WINDOW.as_secs_f64();
+        let estimated = self.previous as f64 * overlap + self.current as f64;
+
+        if estimated < self.limit as f64 {
+            self.current += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn allowed(check: &mut impl FnMut() -> bool, attempts: usize) -> usize {
+        (0..attempts).filter(|_| check()).count()
+    }
+
+    #[test]
+    fn fixed_window_resets_every_minute() {
+        let start = Instant::now();
+        let mut window = FixedWindow::new(3);
+
+        assert_eq!(allowed(&mut || window.check(start), 5), 3);
+        assert_eq!(allowed(&mut || window.check(start + 59 * SECOND), 5), 0);
+        assert_eq!(allowed(&mut || window.check(start + 60 * SECOND), 5), 3);
+    }
+
+    #[test]
+    fn sliding_window_weighs_the_previous_minute() {
+        let start = Instant::now();
+        let mut window = SlidingWindow::new(10);
+
+        assert_eq!(allowed(&mut || window.check(start), 12), 10);
+        // A full previous window still counts in full at the boundary
+        assert_eq!(allowed(&mut || window.check(start + 60 * SECOND), 5), 0);
+        // Halfway through, only half of it does
+        assert_eq!(allowed(&mut || window.check(start + 90 * SECOND), 10), 5);
+        // Two idle windows later everything is forgotten
+        assert_eq!(allowed(&mut || window.check(start + 200 * SECOND), 12), 10);
+    }
+
+    #[test]
+    fn token_bucket_allows_the_burst_then_limits() {
+        let rule = RateLimitRule {
+            path: "/api".to_string(),
+            requests_per_minute: 60,
+            burst_size: Some(2),
+            algorithm: RateLimitAlgorithm::TokenBucket,
+        };
+        let limiter = PathLimiter::new(&rule);
+        assert_eq!(allowed(&mut || limiter.check(), 5), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "requests_per_minute must be > 0")]
+    fn zero_rate_is_rejected() {
+        let rule = RateLimitRule {
+            path: "/api".to_string(),
+            requests_per_minute: 0,
+            burst_size: None,
+            algorithm: RateLimitAlgorithm::FixedWindow,
+        };
+        PathLimiter::new(&rule);
+    }
+
+    #[test]
+    fn token_bucket_and_fixed_window_differ_across_a_window_boundary() {
+        use governor::clock::FakeRelativeClock;
+
+        let rule = |algorithm| RateLimitRule {
+            path: "/api".to_string(),
+            requests_per_minute: 3,
+            burst_size: None,
+            algorithm,
+        };
+        let clock = FakeRelativeClock::default();
+        let bucket = PathLimiter::with_clock(&rule(RateLimitAlgorithm::TokenBucket), clock.clone());
+        let window = PathLimiter::with_clock(&rule(RateLimitAlgorithm::FixedWindow), clock.clone());
+        // Move both clocks to `offset` and count what each lets through.
+        let start = Instant::now();
+        let mut elapsed = Duration::ZERO;
+        let mut at = |offset: Duration| {
+            clock.advance(offset - elapsed);
+            elapsed = offset;
+            let now = start + offset;
+            (allowed(&mut || bucket.check_at(now), 5), allowed(&mut || window.check_at(now), 5))
+        };
+
+        assert_eq!(at(Duration::ZERO), (3, 3));
+        // The bucket has refilled two of its three tokens (one every 20s);
+        // the window is still the first minute's and stays shut.
+        assert_eq!(at(59 * SECOND), (2, 0));
+        // A second later the window starts over in full, while the
+        // bucket only has the token refilled since.
+        assert_eq!(at(60 * SECOND), (1, 3));
+    }
+}
@@ -0,0 +1,275 @@
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper::header::COOKIE;
+use hyper::{HeaderMap, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{ChallengeConfig, ChallengeMode};
+use crate::signing::hex;
+
+pub const COOKIE_NAME: &str = "wardent_challenge";
+
+/// Challenge served to an IP nearing a ban, instead of banning it
+/// outright. A browser solves it with a bit of JS and retries with a
+/// cookie; bots that don't run JS keep collecting violations.
+///
+/// The token is `<expires>.<mac>`, where mac is the hex HMAC-SHA256 of
+/// `<ip>\n<expires>` under the challenge secret, so it can't be reused
+/// from another address. In proof_of_work mode the cookie is
+/// `<token>.<nonce>` and SHA-256 of that must start with `difficulty`
+/// zero bits.
+pub fn response(config: &ChallengeConfig, ip: IpAddr) -> Response<Full<Bytes>> {
+    let expires = now() + config.ttl_secs;
+    let token = format!("{}.{}", expires, mac(config, ip, expires));
+
+    let script = match config.mode {
+        ChallengeMode::Cookie => format!("setCookie({:?});", token),
+        ChallengeMode::ProofOfWork => format!(
+            "solve({:?}, {}).then(setCookie);",
+            token, config.difficulty
+        ),
+    };
+    let body = format!(
+        r#"<!doctype html>
+<html><head><title>Checking your browser</title></head>
+<body><p>Checking your browser&hellip;</p>
+<script>
+function setCookie(value) {{
+  document.cookie = "{name}=" + value + "; path=/; max-age={ttl}; SameSite=Lax";
+  location.reload();
+}}
+function zeroBits(bytes) {{
+  let bits = 0;
+  for (const b of bytes) {{
+    if (b === 0) {{ bits += 8; continue; }}
+    bits += Math.clz32(b) - 24;
+    break;
+  }}
+  return bits;
+}}
+async function solve(token, difficulty) {{
+  const encoder = new TextEncoder();
+  for (let nonce = 0; ; nonce++) {{
+    const value = token + "." + nonce;
+    const digest = await crypto.subtle.digest("SHA-256", encoder.encode(value));
+    if (zeroBits(new Uint8Array(digest)) >= difficulty) return value;
+  }}
+}}
+{script}
+</script>
+<noscript>JavaScript is required to continue.</noscript>
+</body></html>
+"#,
+        name = COOKIE_NAME,
+        ttl = config.ttl_secs,
+        script = script,
+    );
+
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Cache-Control", "no-store")
+        .header("Content-Length", body.len())
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+/// Check the request's challenge cookie for this IP.
+pub fn verify(config: &ChallengeConfig, ip: IpAddr, headers: &HeaderMap) -> bool {
+    let value = match cookie(headers) {
+        Some(value) => value,
+        None => return false,
+    };
+
+    let mut fields = value.splitn(3, '.');
+    let (expires, signature) = match (fields.next(), fields.next()) {
+        (Some(expires), Some(signature)) => (expires, signature),
+        _ => return false,
+    };
+    let nonce = fields.next();
+
+    let expires: u64 = match expires.parse() {
+        Ok(expires) => expires,
+        Err(_) => return false,
+    };
+    if expires < now() {
+        return false;
+    }
+
+    let signature = match unhex(signature) {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let mut mac = keyed(config);
+    mac.update(format!("{}\n{}", ip, expires).as_bytes());
+    if mac.verify_slice(&signature).is_err() {
+        return false;
+    }
+
+    match config.mode {
+        ChallengeMode::Cookie => true,
+        ChallengeMode::ProofOfWork => {
+            nonce.is_some() && leading_zero_bits(&Sha256::digest(value.as_bytes())) >= config.difficulty
+        }
+    }
+}
+
+fn cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE_NAME)
+        .map(|(_, value)| value)
+}
+
+fn mac(config: &ChallengeConfig, ip: IpAddr, expires: u64) -> String {
+    let mut mac = keyed(config);
+    mac.update(format!("{}\n{}", ip, expires).as_bytes());
+    hex(&mac.finalize().into_bytes())
+}
+
+fn keyed(config: &ChallengeConfig) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(config.secret.as_bytes())
+        .expect("HMAC accepts keys of any length")
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        if *byte == 0 {
+            bits += 8;
+            continue;
+        }
+        bits += byte.leading_zeros();
+        break;
+    }
+    bits
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    const IP: &str = "203.0.113.9";
+
+    fn config(mode: &str) -> ChallengeConfig {
+        let config = test_config(&format!(
+            "[rate_limit.challenge]\nsecret = \"s3cret\"\nmode = \"{mode}\"\ndifficulty = 8"
+        ));
+        config.rate_limit.challenge.unwrap()
+    }
+
+    fn ip() -> IpAddr {
+        IP.parse().unwrap()
+    }
+
+    fn token(config: &ChallengeConfig, expires: u64) -> String {
+        format!("{}.{}", expires, mac(config, ip(), expires))
+    }
+
+    fn with_cookie(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, format!("theme=dark; {COOKIE_NAME}={value}").parse().unwrap());
+        headers
+    }
+
+    /// The first nonce for which the proof of work does (or doesn't)
+    /// reach the configured difficulty.
+    fn nonce_for(config: &ChallengeConfig, token: &str, solves: bool) -> String {
+        (0u64..)
+            .map(|nonce| format!("{token}.{nonce}"))
+            .find(|value| {
+                (leading_zero_bits(&Sha256::digest(value.as_bytes())) >= config.difficulty) == solves
+            })
+            .unwrap()
+    }
+
+    /// The token the challenge page sets as its cookie.
+    fn issued_token(config: &ChallengeConfig) -> String {
+        use http_body_util::BodyExt;
+
+        let response = response(config, ip());
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body = futures_util::FutureExt::now_or_never(response.into_body().collect())
+            .unwrap()
+            .unwrap()
+            .to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let start = body.find("setCookie(\"").unwrap() + "setCookie(\"".len();
+        body[start..].split('"').next().unwrap().to_string()
+    }
+
+    #[test]
+    fn issued_cookie_verifies_for_its_ip_only() {
+        let config = config("cookie");
+        let token = issued_token(&config);
+
+        assert!(verify(&config, ip(), &with_cookie(&token)));
+        assert!(!verify(&config, "198.51.100.1".parse().unwrap(), &with_cookie(&token)));
+        assert!(!verify(&config, ip(), &HeaderMap::new()));
+    }
+
+    #[test]
+    fn expired_cookie_is_rejected() {
+        let config = config("cookie");
+        assert!(!verify(&config, ip(), &with_cookie(&token(&config, now() - 1))));
+    }
+
+    #[test]
+    fn tampered_cookie_is_rejected() {
+        let config = config("cookie");
+        let expires = now() + 60;
+        let token = token(&config, expires);
+
+        let later = token.replacen(&expires.to_string(), &(expires + 3600).to_string(), 1);
+        assert!(!verify(&config, ip(), &with_cookie(&later)));
+
+        let mut flipped = token.clone();
+        let last = if flipped.ends_with('0') { "1" } else { "0" };
+        flipped.replace_range(flipped.len() - 1.., last);
+        assert!(!verify(&config, ip(), &with_cookie(&flipped)));
+
+        assert!(!verify(&config, ip(), &with_cookie(&format!("{expires}.zz"))));
+    }
+
+    #[test]
+    fn proof_of_work_must_meet_the_difficulty() {
+        let config = config("proof_of_work");
+        let token = token(&config, now() + 60);
+
+        assert!(!verify(&config, ip(), &with_cookie(&token)));
+        assert!(verify(&config, ip(), &with_cookie(&nonce_for(&config, &token, true))));
+        assert!(!verify(&config, ip(), &with_cookie(&nonce_for(&config, &token, false))));
+    }
+
+    #[test]
+    fn leading_zero_bits_counts_across_bytes() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+}
@@ -0,0 +1,85 @@
+use hyper::HeaderMap;
+use std::net::IpAddr;
+
+use crate::config::ServerConfig;
+
+/// Resolve the client IP used everywhere: rate limiting, history, logs
+/// and the X-Forwarded-For sent upstream.
+///
+/// Stack: client -> WAF -> nginx -> Wardent. Sources, in order:
+/// 1. The first IP in X-Forwarded-For (`<client>, <waf>, <nginx>`)
+/// 2. X-Real-IP
+/// 3. The TCP peer address
+///
+/// Headers are only read when the peer is a trusted proxy: any peer if
+/// `server.trusted_proxies` is empty, otherwise one listed there.
+/// Unparseable header values fall through to the next source.
+pub fn resolve(peer: IpAddr, headers: &HeaderMap, config: &ServerConfig) -> IpAddr {
+    if !config.trusted_proxies.is_empty() && !config.trusted_proxies.contains(&peer) {
+        return peer;
+    }
+
+    let forwarded_for = headers
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .and_then(|ip| ip.trim().parse().ok());
+    if let Some(ip) = forwarded_for {
+        return ip;
+    }
+
+    let real_ip = headers
+        .get("x-real-ip")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|ip| ip.trim().parse().ok());
+    if let Some(ip) = real_ip {
+        return ip;
+    }
+
+    // Without headers this is nginx's IP, but better than nothing
+    peer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    const PEER: &str = "10.0.0.2";
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn resolved(pairs: &[(&'static str, &'static str)], overrides: &str) -> IpAddr {
+        let config = test_config(overrides);
+        resolve(PEER.parse().unwrap(), &headers(pairs), &config.server)
+    }
+
+    #[test]
+    fn first_forwarded_for_ip_wins() {
+        let pairs = [("x-forwarded-for", "203.0.113.7, 10.0.0.1, 10.0.0.2"), ("x-real-ip", "198.51.100.1")];
+        assert_eq!(resolved(&pairs, ""), "203.0.113.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn falls_back_to_real_ip_then_peer() {
+        assert_eq!(resolved(&[("x-real-ip", " 198.51.100.1 ")], ""), "198.51.100.1".parse::<IpAddr>().unwrap());
+        let garbage = [("x-forwarded-for", "unknown"), ("x-real-ip", "nope")];
+        assert_eq!(resolved(&garbage, ""), PEER.parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn headers_are_only_trusted_from_listed_proxies() {
+        let pairs = [("x-forwarded-for", "203.0.113.7")];
+        let trusted = "server.trusted_proxies = [\"10.0.0.2\"]";
+        assert_eq!(resolved(&pairs, trusted), "203.0.113.7".parse::<IpAddr>().unwrap());
+
+        let untrusted = "server.trusted_proxies = [\"10.0.0.1\"]";
+        assert_eq!(resolved(&pairs, untrusted), PEER.parse::<IpAddr>().unwrap());
+    }
+}
@@ -1,10 +1,168 @@
 use serde::Deserialize;
+use std::collections::HashSet;
 use std::fs;
-use std::path::Path;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+use crate::ratelimit::MAX_VIOLATIONS;
+
+/// Keys renamed since older config files, as (old, new) dotted paths.
+/// Old keys still load, with a warning naming the replacement. Add an
+/// entry here whenever a key is renamed.
+const RENAMED_KEYS: &[(&str, &str)] = &[];
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ProxyConfig {
     pub upstream: String,
     pub secret_key: String,
+    /// Add a Content-Type to upstream responses that omit one,
+    /// so browsers don't fall back to content sniffing.
+    #[serde(default)]
+    pub enforce_content_type: bool,
+    #[serde(default = "default_content_type")]
+    pub default_content_type: String,
+    /// Once response headers arrive, read the upstream body with this
+    /// idle timeout instead of the remaining per-path timeout. A steady
+    /// download may then take up to twice the per-path timeout.
+    #[serde(default)]
+    pub upstream_idle_timeout_secs: Option<u64>,
+    /// Time allowed for the upstream's response headers, from when the
+    /// request is sent. Within the per-path timeout, not added to it.
+    #[serde(default)]
+    pub ttfb_timeout_secs: Option<u64>,
+    /// Forward POSTs carrying X-HTTP-Method-Override as the overriding
+    /// method (PUT, PATCH or DELETE only). The header is stripped.
+    #[serde(default)]
+    pub honor_method_override: bool,
+    #[serde(default)]
+    pub signing: Option<SigningConfig>,
+    /// What to do when the upstream closes before sending its declared
+    /// Content-Length.
+    #[serde(default)]
+    pub truncated_response: TruncatedResponseAction,
+    /// What to do when the upstream redirects a request to the URL it
+    /// was asked for, e.g. an APPEND_SLASH loop.
+    #[serde(default)]
+    pub redirect_loop: RedirectLoopAction,
+    /// How the client's Accept-Encoding is passed to the upstream.
+    #[serde(default)]
+    pub upstream_accept_encoding: UpstreamAcceptEncoding,
+    /// Send an explicit Content-Length: 0 on bodyless POST/PUT/PATCH
+    /// requests that didn't carry one, for backends that require it.
+    #[serde(default)]
+    pub normalize_empty_body: bool,
+    /// Add or remove the trailing slash on request paths before any
+    /// rule matching, saving Django's APPEND_SLASH redirect.
+    #[serde(default)]
+    pub trailing_slash: TrailingSlash,
+    /// Path prefixes left as they are, e.g. ACME tokens.
+    #[serde(default = "default_trailing_slash_exempt")]
+    pub trailing_slash_exempt: Vec<String>,
+    /// X-Forwarded-Proto sent upstream, unless a forwarded_proto_override
+    /// rule matches.
+    #[serde(default = "default_forwarded_proto")]
+    pub forwarded_proto: String,
+    /// Header name case on the wire to the upstream. hyper stores names
+    /// lowercased, so the client's original case is never kept.
+    #[serde(default)]
+    pub normalize_header_case: HeaderCase,
+    /// Join repeated request headers into one comma-separated header
+    /// (Cookie with "; ") before forwarding.
+    #[serde(default)]
+    pub merge_duplicate_headers: bool,
+    /// Enable TCP Fast Open on upstream connections (Linux only).
+    #[serde(default)]
+    pub upstream_tcp_fast_open: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderCase {
+    /// `content-type`
+    #[default]
+    Lowercase,
+    /// `Content-Type`, for backends that compare names case-sensitively.
+    TitleCase,
+}
+
+/// Paths whose last segment looks like a file (has an extension) and
+/// the root path are never changed.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailingSlash {
+    #[default]
+    Preserve,
+    Add,
+    Remove,
+}
+
+fn default_trailing_slash_exempt() -> Vec<String> {
+    vec!["/.well-known/".to_string()]
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamAcceptEncoding {
+    /// Forward the client's Accept-Encoding unchanged.
+    #[default]
+    Passthrough,
+    /// Ask the upstream for an uncompressed body (`identity`), so
+    /// response rewrites can see it.
+    Strip,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TruncatedResponseAction {
+    /// Answer 502 instead of relaying a partial body.
+    #[default]
+    Fail,
+    /// Relay the bytes received so far, with a warning logged.
+    Forward,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectLoopAction {
+    /// Relay redirects without checking them.
+    #[default]
+    Ignore,
+    /// Relay the redirect, with a warning logged.
+    Warn,
+    /// Answer 502 instead of relaying the redirect.
+    Fail,
+}
+
+/// HMAC-SHA256 signing of forwarded requests, so the backend can tell
+/// requests that came through Wardent from ones that bypassed it.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SigningConfig {
+    pub secret: String,
+    #[serde(default = "default_signed_components")]
+    pub components: Vec<SignedComponent>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SignedComponent {
+    Method,
+    Path,
+    Query,
+    Timestamp,
+    Body,
+}
+
+fn default_signed_components() -> Vec<SignedComponent> {
+    vec![SignedComponent::Method, SignedComponent::Path, SignedComponent::Timestamp]
+}
+
+fn default_forwarded_proto() -> String {
+    "https".to_string()
+}
+
+fn default_content_type() -> String {
+    "application/octet-stream".to_string()
 }
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
@@ -16,35 +174,507 @@ pub struct Config {
     pub error_redirects: ErrorRedirects,
     #[serde(default)]
     pub timeout_override: Vec<TimeoutOverride>,
+    #[serde(default)]
+    pub forwarded_proto_override: Vec<ForwardedProtoOverride>,
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    #[serde(default)]
+    pub response_rewrite: Vec<ResponseRewrite>,
+    #[serde(default)]
+    pub aggregate: Vec<AggregateRoute>,
+    #[serde(default)]
+    pub session_ordering: Option<SessionOrderingConfig>,
+    #[serde(default)]
+    pub replay_protection: Option<ReplayProtectionConfig>,
+    #[serde(default)]
+    pub jwt: Option<JwtConfig>,
+    #[serde(default)]
+    pub query_schema: Vec<QuerySchemaRule>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub forward_proxy: Option<ForwardProxyConfig>,
+    /// Fail to load on unknown keys instead of warning, to catch typos.
+    #[serde(default)]
+    pub reject_unknown_keys: bool,
+}
+
+/// Opt-in forward proxy mode: CONNECT requests to these `host:port`
+/// destinations are tunnelled, all others refused. Rate limits and bans
+/// still apply.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ForwardProxyConfig {
+    pub allowed_destinations: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct LoggingConfig {
+    /// Log, per request, which user-agent filter entry, rate limit rule,
+    /// timeout override and route it matched. Costs an extra pass over
+    /// the rules, so keep it for debugging.
+    #[serde(default)]
+    pub explain: bool,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ServerConfig {
     pub listen_addr: String,
+    /// Host header allowlist, like Django's ALLOWED_HOSTS. Entries are
+    /// exact hosts, `*.example.com` (subdomains only) or `.example.com`
+    /// (the domain and its subdomains). Empty disables the check.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    #[serde(default)]
+    pub invalid_host_action: InvalidHostAction,
+    /// Host to substitute when invalid_host_action = "rewrite".
+    #[serde(default)]
+    pub canonical_host: Option<String>,
+    /// Path prefixes that skip the host check.
+    #[serde(default = "default_host_check_exempt")]
+    pub host_check_exempt: Vec<String>,
+    /// Peers allowed to set X-Forwarded-For / X-Real-IP. Empty trusts
+    /// every peer, for when Wardent is only reachable through nginx.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Max requests per second on a single connection. Past it the
+    /// request gets 429 and the connection is closed, which stops
+    /// pipelined floods faster than the per-minute IP limits can.
+    #[serde(default)]
+    pub max_requests_per_connection_per_sec: Option<u32>,
+    /// Close client connections that read responses slower than this
+    /// many bytes/sec, measured over min_client_read_window_secs.
+    #[serde(default)]
+    pub min_client_read_bps: Option<u64>,
+    #[serde(default = "default_min_client_read_window_secs")]
+    pub min_client_read_window_secs: u64,
+    /// Enable TCP Fast Open on the listener (Linux only; logged and
+    /// ignored elsewhere).
+    #[serde(default)]
+    pub tcp_fast_open: bool,
+    #[serde(default)]
+    pub load_shed: Option<LoadShedConfig>,
+}
+
+/// Answer 503 to new requests while more than max_active_requests are
+/// in flight. Paths under critical_paths are never shed.
+#[derive(Debug, Deserialize, Clone)]
+pub struct LoadShedConfig {
+    pub max_active_requests: usize,
+    #[serde(default)]
+    pub critical_paths: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidHostAction {
+    #[default]
+    Reject,
+    Rewrite,
+}
+
+fn default_min_client_read_window_secs() -> u64 {
+    10
+}
+
+fn default_host_check_exempt() -> Vec<String> {
+    vec!["/.well-known/acme-challenge/".to_string()]
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct LimitsConfig {
     pub max_body_size: u64,
     pub default_timeout_secs: u64,
+    /// When set, the request body is read with an idle timeout (reset on
+    /// every received chunk) instead of the total per-path timeout. A
+    /// steady upload may then take up to twice the per-path timeout.
+    #[serde(default)]
+    pub body_idle_timeout_secs: Option<u64>,
+    /// Content-Encodings accepted on request bodies; others get 400.
+    /// Unset accepts any.
+    #[serde(default)]
+    pub allowed_request_encodings: Option<Vec<String>>,
+    /// Inflate gzip/deflate request bodies up to this many bytes before
+    /// forwarding; larger ones get 413. Other codings (br, zstd, ...)
+    /// get 415 while this is set, since their size can't be bounded.
+    #[serde(default)]
+    pub max_decompressed_size: Option<u64>,
+    /// Upstream response bodies larger than this get 502 instead of being
+    /// buffered. Unset means no limit.
+    #[serde(default)]
+    pub max_response_size: Option<u64>,
+    /// Max parameters in a query string; more get 400.
+    #[serde(default)]
+    pub max_query_params: Option<usize>,
+    /// Max occurrences of any one parameter name, against
+    /// parameter-pollution attacks like `a=1&a=2&...`.
+    #[serde(default)]
+    pub max_repeated_param: Option<usize>,
+    /// Cap on request line, headers and body together; each part may be
+    /// within its own limit while the total isn't.
+    #[serde(default)]
+    pub max_request_size: Option<u64>,
+    /// Timeout for establishing the upstream TCP connection, separate
+    /// from the request timeout. Unset means no connect timeout.
+    #[serde(default)]
+    pub connect_timeout_secs: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct RateLimitConfig {
     pub requests_per_minute: u32,
     pub burst_size: u32,
+    #[serde(default)]
+    pub history: Option<HistoryConfig>,
+    #[serde(default)]
+    pub rule: Vec<RateLimitRule>,
+    /// Trusted header carrying the authenticated user, set by the layer
+    /// in front of Wardent. Must not be client-controllable.
+    #[serde(default)]
+    pub user_header: Option<String>,
+    /// Max in-flight requests per user, at least 1; requests without a
+    /// user are counted per client IP instead.
+    #[serde(default)]
+    pub max_concurrent_per_user: Option<usize>,
+    /// Proxy-wide requests per second ceiling, regardless of client.
+    #[serde(default)]
+    pub global_rps: Option<u32>,
+    /// Client IPs that bypass the global ceiling.
+    #[serde(default)]
+    pub global_rps_exempt: Vec<IpAddr>,
+    /// Soft cap on IPs with their own limiter. Beyond it, unseen IPs are
+    /// handled per ip_overflow until cleanup frees room. Also caps the
+    /// IPs with request history (10,000 when unset).
+    #[serde(default)]
+    pub max_tracked_ips: Option<usize>,
+    #[serde(default)]
+    pub ip_overflow: IpOverflowAction,
+    #[serde(default)]
+    pub challenge: Option<ChallengeConfig>,
+    /// Shard count for the per-IP limiter, ban and history maps (a power
+    /// of two above 1). More shards mean less lock contention on hot
+    /// IPs at high request rates. Defaults to dashmap's 4x CPU cores.
+    #[serde(default)]
+    pub shards: Option<usize>,
+}
+
+/// Serve a JS challenge to IPs nearing a ban. An IP that solves it has
+/// its violation count reduced once; one that ignores it is banned as
+/// usual.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ChallengeConfig {
+    #[serde(default)]
+    pub mode: ChallengeMode,
+    /// Signs challenge cookies; changing it invalidates issued ones.
+    pub secret: String,
+    /// Violations after which the challenge is served instead of 429.
+    /// Must be below the 5 that get an IP banned.
+    #[serde(default = "default_challenge_after")]
+    pub after_violations: u32,
+    /// Leading zero bits required in proof_of_work mode.
+    #[serde(default = "default_challenge_difficulty")]
+    pub difficulty: u32,
+    #[serde(default = "default_challenge_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChallengeMode {
+    /// The page just sets a signed cookie; stops clients without JS.
+    #[default]
+    Cookie,
+    /// The page must find a SHA-256 proof of work first.
+    ProofOfWork,
+}
+
+fn default_challenge_after() -> u32 {
+    3
+}
+
+fn default_challenge_difficulty() -> u32 {
+    16
+}
+
+fn default_challenge_ttl_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpOverflowAction {
+    /// Untracked IPs share one limiter with the per-IP quota.
+    #[default]
+    Shared,
+    /// Untracked IPs get 503 straight away.
+    Reject,
+}
+
+/// Per-path rate limit, applied on top of the global per-IP limit.
+/// Rules are checked in order; the first prefix match wins.
+///
+/// A path with `{name}` segments, e.g. `/users/{id}/`, is limited per
+/// captured value instead of per IP, so one resource can't be hammered
+/// from many addresses.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimitRule {
+    pub path: String,
+    pub requests_per_minute: u32,
+    /// Only used by the token bucket; defaults to requests_per_minute.
+    #[serde(default)]
+    pub burst_size: Option<u32>,
+    #[serde(default)]
+    pub algorithm: RateLimitAlgorithm,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAlgorithm {
+    /// Tokens refill continuously; allows bursts up to burst_size,
+    /// then a steady requests_per_minute. Suits bursty APIs.
+    #[default]
+    TokenBucket,
+    /// Simple counter reset every minute. Cheap, but a client can send
+    /// up to twice the limit across a window boundary.
+    FixedWindow,
+    /// Weighs the previous window's count by how much of it still
+    /// overlaps the last minute. Strict, with no boundary burst.
+    SlidingWindow,
+}
+
+/// Per-IP request history kept for incident forensics.
+/// Only request metadata is recorded, never bodies.
+#[derive(Debug, Deserialize, Clone)]
+pub struct HistoryConfig {
+    pub size: usize,
+    pub retention_secs: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct FilterConfig {
     pub blocked_user_agents: Vec<String>,
     pub redirect_url: String,
+    /// Reject requests whose header values contain control characters.
+    #[serde(default)]
+    pub reject_control_chars: bool,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct TimeoutOverride {
     pub path: String,
+    /// Methods the rule applies to; empty means all.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub connect_timeout_secs: Option<u64>,
+    /// Latency target for the whole request. Past it the client gets
+    /// 503 right away, even if the upstream would still answer within
+    /// timeout_secs.
+    #[serde(default)]
+    pub max_response_ms: Option<u64>,
+}
+
+/// Per-path X-Forwarded-Proto, e.g. "http" for internal callbacks that
+/// must not think they're behind TLS.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ForwardedProtoOverride {
+    pub path: String,
+    pub proto: String,
+}
+
+/// Literal find/replace on buffered upstream response bodies,
+/// e.g. swapping an internal base URL for the public one.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ResponseRewrite {
+    #[serde(default = "default_rewrite_content_type")]
+    pub content_type: String,
+    pub find: String,
+    pub replace: String,
+}
+
+fn default_rewrite_content_type() -> String {
+    "application/json".to_string()
+}
+
+/// A virtual path answered by fanning out to several upstream
+/// endpoints and merging their JSON bodies under named keys.
+#[derive(Debug, Deserialize, Clone)]
+pub struct AggregateRoute {
+    pub path: String,
+    /// Fail the whole response with 502 if any sub-request fails,
+    /// instead of reporting the error under that key.
+    #[serde(default)]
+    pub fail_on_error: bool,
+    /// Take precedence over timeout_override rules and limits for the
+    /// route's sub-requests.
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub connect_timeout_secs: Option<u64>,
+    pub request: Vec<AggregateRequest>,
+}
+
+/// The timeouts that apply to one request, from [`Config::timeouts`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub total_secs: u64,
+    pub connect_secs: Option<u64>,
+    pub max_response_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AggregateRequest {
+    pub key: String,
+    pub path: String,
+}
+
+/// Process requests sharing a session header one at a time, in arrival
+/// order. Trades throughput for ordering on those sessions only.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SessionOrderingConfig {
+    pub header: String,
+    /// Requests allowed to wait behind the one in flight; more get 503.
+    #[serde(default = "default_session_queue_depth")]
+    pub max_queue_depth: usize,
+}
+
+fn default_session_queue_depth() -> usize {
+    8
+}
+
+/// Allowed query parameters for a path prefix. Requests with a
+/// malformed or missing required parameter get 400.
+#[derive(Debug, Deserialize, Clone)]
+pub struct QuerySchemaRule {
+    pub path: String,
+    #[serde(default)]
+    pub unknown: UnknownQueryParams,
+    #[serde(default)]
+    pub param: Vec<QueryParam>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownQueryParams {
+    /// Answer 400.
+    #[default]
+    Reject,
+    /// Drop them from the forwarded URI.
+    Strip,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct QueryParam {
+    pub name: String,
+    #[serde(default)]
+    pub kind: QueryParamKind,
+    #[serde(default)]
+    pub required: bool,
+    /// Allowed values when kind = "enum".
+    #[serde(default)]
+    pub values: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryParamKind {
+    #[default]
+    String,
+    Int,
+    /// true, false, 1 or 0.
+    Bool,
+    Enum,
+}
+
+/// Require X-Nonce and X-Timestamp on these path prefixes and reject
+/// stale timestamps and reused nonces.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReplayProtectionConfig {
+    pub paths: Vec<String>,
+    /// Max clock difference, either way, for X-Timestamp.
+    #[serde(default = "default_replay_tolerance_secs")]
+    pub tolerance_secs: u64,
+    /// Cap on remembered nonces; new ones get 503 while it's full.
+    #[serde(default = "default_replay_max_nonces")]
+    pub max_nonces: usize,
+    /// Status for requests missing the headers (400 or 401).
+    #[serde(default = "default_replay_missing_status")]
+    pub missing_status: u16,
+}
+
+/// Validate `Authorization: Bearer` JWTs on these path prefixes and pass
+/// selected claims upstream as headers. Invalid tokens get 401. Exactly
+/// one of secret (HS256/384/512) or jwks_url (RSA/EC keys) must be set.
+#[derive(Debug, Deserialize, Clone)]
+pub struct JwtConfig {
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    /// How long a fetched key set is used before it's fetched again.
+    /// Fetches are at least 30s apart, including retries after a failure.
+    #[serde(default = "default_jwks_refresh_secs")]
+    pub jwks_refresh_secs: u64,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub audience: Option<String>,
+    /// Allowed clock skew for exp and nbf.
+    #[serde(default = "default_jwt_leeway_secs")]
+    pub leeway_secs: u64,
+    /// Claims to forward. The headers are stripped from every incoming
+    /// request, so clients can't set them on any path.
+    #[serde(default)]
+    pub claim: Vec<JwtClaimHeader>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct JwtClaimHeader {
+    pub name: String,
+    pub header: String,
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+    300
+}
+
+fn default_jwt_leeway_secs() -> u64 {
+    30
+}
+
+fn default_replay_tolerance_secs() -> u64 {
+    300
+}
+
+fn default_replay_max_nonces() -> usize {
+    100_000
+}
+
+fn default_replay_missing_status() -> u16 {
+    400
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AdminConfig {
+    pub listen_addr: String,
+    /// Header read and request handling timeout for admin connections,
+    /// independent of the proxy's per-path timeouts.
+    #[serde(default = "default_admin_timeout_secs")]
     pub timeout_secs: u64,
+    /// If listen_addr can't be bound (e.g. an overlapping instance during
+    /// a rolling restart), log a warning and serve traffic without the
+    /// admin API instead of failing startup.
+    #[serde(default)]
+    pub optional: bool,
+    /// Bearer token required on every admin route. Without one,
+    /// listen_addr must be a loopback address.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+fn default_admin_timeout_secs() -> u64 {
+    5
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -54,24 +684,528 @@ pub struct ErrorRedirects {
     pub body_too_large: String,
     pub timeout: String,
     pub bad_gateway: String,
+    /// Cache-Control added to error (4xx/5xx) and redirect (3xx)
+    /// responses that Wardent generates and that don't set one, so
+    /// intermediaries don't keep serving a transient error or a bot
+    /// redirect. Upstream responses are left as they are.
+    /// Empty disables it.
+    #[serde(default = "default_error_cache_control")]
+    pub cache_control: String,
+}
+
+fn default_error_cache_control() -> String {
+    "no-store".to_string()
 }
 
 impl Config {
+    /// Load the config file and any files it includes.
+    ///
+    /// A top-level `include = ["tenants/a.toml", ...]` lists more files,
+    /// relative to the including file, which may include others in
+    /// turn. Each is merged over the including file in list order:
+    /// tables merge key by key, arrays are appended, and any other value
+    /// replaces the earlier one. A file reached more than once, e.g.
+    /// shared defaults included by two tenants, is only merged the first
+    /// time. A file that includes itself, directly or through others, is
+    /// an error.
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
-        let content = fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&content)?;
+        let table = load_table(path.as_ref(), &mut Vec::new(), &mut HashSet::new())?;
+
+        let mut unknown = Vec::new();
+        let config: Config = serde_ignored::deserialize(toml::Value::Table(table), |key| {
+            unknown.push(key.to_string())
+        })?;
+
+        if !unknown.is_empty() {
+            if config.reject_unknown_keys {
+                return Err(format!("unknown config keys: {}", unknown.join(", ")).into());
+            }
+            for key in &unknown {
+                warn!(key = %key, "Ignoring unknown config key");
+            }
+        }
+        config.validate()?;
         Ok(config)
     }
 
-    /// Get the timeout for a given request path.
-    /// Checks timeout_override rules in order, returns first match.
-    /// Falls back to default_timeout_secs.
-    pub fn timeout_for_path(&self, path: &str) -> u64 {
-        for rule in &self.timeout_override {
-            if path.starts_with(&rule.path) {
-                return rule.timeout_secs;
+    /// Reject values that deserialize fine but can't work.
+    fn validate(&self) -> Result<(), String> {
+        if let Some(replay) = &self.replay_protection {
+            if !matches!(replay.missing_status, 400 | 401) {
+                return Err(format!(
+                    "replay_protection.missing_status must be 400 or 401, got {}",
+                    replay.missing_status
+                ));
+            }
+        }
+        if self.rate_limit.max_concurrent_per_user == Some(0) {
+            return Err("rate_limit.max_concurrent_per_user must be > 0".to_string());
+        }
+        if let Some(challenge) = &self.rate_limit.challenge {
+            if challenge.after_violations == 0 || challenge.after_violations >= MAX_VIOLATIONS {
+                return Err(format!(
+                    "rate_limit.challenge.after_violations must be between 1 and {}, got {}",
+                    MAX_VIOLATIONS - 1,
+                    challenge.after_violations
+                ));
+            }
+        }
+        let content_type = &self.proxy.default_content_type;
+        if !content_type.contains('/') || hyper::header::HeaderValue::from_str(content_type).is_err() {
+            return Err(format!(
+                "proxy.default_content_type must be a media type like text/plain, got {:?}",
+                content_type
+            ));
+        }
+        Ok(())
+    }
+
+    /// Find the aggregate route for an exact request path.
+    pub fn aggregate_for_path(&self, path: &str) -> Option<&AggregateRoute> {
+        self.aggregate.iter().find(|route| route.path == path)
+    }
+
+    /// Resolve the timeouts for a request. Each one is taken from the
+    /// most specific level that sets it: the aggregate route, then the
+    /// first timeout_override rule matching the method and path, then
+    /// limits.
+    pub fn timeouts(&self, method: &str, path: &str, route: Option<&AggregateRoute>) -> Timeouts {
+        let rule = self.timeout_rule_for(method, path);
+        Timeouts {
+            total_secs: route
+                .and_then(|route| route.timeout_secs)
+                .or(rule.map(|rule| rule.timeout_secs))
+                .unwrap_or(self.limits.default_timeout_secs),
+            connect_secs: route
+                .and_then(|route| route.connect_timeout_secs)
+                .or(rule.and_then(|rule| rule.connect_timeout_secs))
+                .or(self.limits.connect_timeout_secs),
+            max_response_ms: rule.and_then(|rule| rule.max_response_ms),
+        }
+    }
+
+    /// The timeout_override rule a request matches, if any.
+    pub fn timeout_rule_for(&self, method: &str, path: &str) -> Option<&TimeoutOverride> {
+        self.timeout_override.iter().find(|rule| {
+            path.starts_with(&rule.path)
+                && (rule.methods.is_empty()
+                    || rule.methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+        })
+    }
+
+    /// Get the X-Forwarded-Proto for a given request path.
+    /// Checks forwarded_proto_override rules in order, returns first
+    /// match. Falls back to proxy.forwarded_proto.
+    pub fn forwarded_proto_for_path(&self, path: &str) -> &str {
+        match self
+            .forwarded_proto_override
+            .iter()
+            .find(|rule| path.starts_with(&rule.path))
+        {
+            Some(rule) => {
+                debug!(
+                    path = path,
+                    rule = %rule.path,
+                    proto = %rule.proto,
+                    "X-Forwarded-Proto override"
+                );
+                &rule.proto
+            }
+            None => &self.proxy.forwarded_proto,
+        }
+    }
+}
+
+/// Read a config file with its includes merged in. `stack` holds the
+/// files currently being loaded, to catch include cycles, and `loaded`
+/// every file read so far, so one included twice is only merged once.
+fn load_table(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    loaded: &mut HashSet<PathBuf>,
+) -> Result<toml::Table, Box<dyn std::error::Error>> {
+    let canonical = fs::canonicalize(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    if stack.contains(&canonical) {
+        return Err(format!("circular config include of {}", path.display()).into());
+    }
+    if !loaded.insert(canonical.clone()) {
+        debug!(file = %path.display(), "Config file already included, skipping");
+        return Ok(toml::Table::new());
+    }
+
+    let content = fs::read_to_string(&canonical)?;
+    let mut table: toml::Table = toml::from_str(&content)
+        .map_err(|e| format!("{}: {e}", path.display()))?;
+    migrate_renamed_keys(&mut table, RENAMED_KEYS);
+
+    let includes = match table.remove("include") {
+        None => return Ok(table),
+        Some(toml::Value::Array(includes)) => includes,
+        Some(_) => return Err(format!("{}: include must be an array", path.display()).into()),
+    };
+
+    stack.push(canonical.clone());
+    let dir = canonical.parent().unwrap_or(Path::new("."));
+    for include in includes {
+        let include = include
+            .as_str()
+            .ok_or_else(|| format!("{}: include entries must be strings", path.display()))?;
+        debug!(file = %path.display(), include = include, "Including config file");
+        let included = load_table(&dir.join(include), stack, loaded)?;
+        merge_tables(&mut table, included);
+    }
+    stack.pop();
+
+    Ok(table)
+}
+
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (Some(toml::Value::Array(base)), toml::Value::Array(overlay)) => base.extend(overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Move renamed keys to their current names. If both are set, the new
+/// key wins and the old one is dropped.
+fn migrate_renamed_keys(table: &mut toml::Table, renames: &[(&str, &str)]) {
+    for (old, new) in renames {
+        let value = match take_key(table, old) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        if get_key(table, new).is_some() {
+            warn!(old = %old, new = %new, "Deprecated config key ignored, new key is also set");
+            continue;
+        }
+        warn!(old = %old, new = %new, "Deprecated config key, use the new name");
+        insert_key(table, new, value);
+    }
+}
+
+fn take_key(table: &mut toml::Table, path: &str) -> Option<toml::Value> {
+    match path.split_once('.') {
+        Some((head, rest)) => take_key(table.get_mut(head)?.as_table_mut()?, rest),
+        None => table.remove(path),
+    }
+}
+
+fn get_key<'a>(table: &'a toml::Table, path: &str) -> Option<&'a toml::Value> {
+    match path.split_once('.') {
+        Some((head, rest)) => get_key(table.get(head)?.as_table()?, rest),
+        None => table.get(path),
+    }
+}
+
+fn insert_key(table: &mut toml::Table, path: &str, value: toml::Value) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            let entry = table
+                .entry(head)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if let Some(inner) = entry.as_table_mut() {
+                insert_key(inner, rest, value);
+            }
+        }
+        None => {
+            table.insert(path.to_string(), value);
+        }
+    }
+}
+
+/// A minimal valid config with `overrides` (TOML) merged over it, for
+/// tests elsewhere in the crate.
+#[cfg(test)]
+pub(crate) fn test_config(overrides: &str) -> Config {
+    const BASE: &str = r#"
+        [server]
+        listen_addr = "127.0.0.1:0"
+
+        [proxy]
+        upstream = "http://127.0.0.1:9"
+        secret_key = "test"
+
+        [limits]
+        max_body_size = 1024
+        default_timeout_secs = 30
+
+        [rate_limit]
+        requests_per_minute = 60
+        burst_size = 10
+
+        [filter]
+        blocked_user_agents = []
+        redirect_url = "https://example.com/"
+
+        [error_redirects]
+        rate_limited = "/429"
+        banned = "/403"
+        body_too_large = "/413"
+        timeout = "/408"
+        bad_gateway = "/502"
+    "#;
+
+    let mut table: toml::Table = toml::from_str(BASE).unwrap();
+    merge_tables(&mut table, toml::from_str(overrides).unwrap());
+    toml::Value::Table(table).try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    const RENAMES: &[(&str, &str)] = &[("server.listen", "server.listen_addr")];
+
+    /// Run `f` with tracing output captured, returning what was logged.
+    fn captured_logs(f: impl FnOnce()) -> String {
+        #[derive(Clone, Default)]
+        struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
             }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+        let logs = buffer.0.lock().unwrap().clone();
+        String::from_utf8(logs).unwrap()
+    }
+
+    #[test]
+    fn deprecated_key_loads_with_warning() {
+        let mut table: toml::Table = toml::from_str("[server]\nlisten = \"0.0.0.0:80\"").unwrap();
+        let logs = captured_logs(|| migrate_renamed_keys(&mut table, RENAMES));
+
+        assert_eq!(get_key(&table, "server.listen_addr").and_then(|v| v.as_str()), Some("0.0.0.0:80"));
+        assert!(get_key(&table, "server.listen").is_none());
+        assert!(logs.contains("Deprecated config key, use the new name"), "{logs}");
+    }
+
+    #[test]
+    fn new_key_wins_over_deprecated_one() {
+        let mut table: toml::Table =
+            toml::from_str("[server]\nlisten = \"old\"\nlisten_addr = \"new\"").unwrap();
+        let logs = captured_logs(|| migrate_renamed_keys(&mut table, RENAMES));
+
+        assert_eq!(get_key(&table, "server.listen_addr").and_then(|v| v.as_str()), Some("new"));
+        assert!(get_key(&table, "server.listen").is_none());
+        assert!(logs.contains("Deprecated config key ignored"), "{logs}");
+    }
+
+    /// A fresh directory under the system temp dir holding `files`.
+    fn config_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wardent-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (file, content) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
         }
-        self.limits.default_timeout_secs
+        dir
+    }
+
+    fn load(path: &Path) -> Result<toml::Table, Box<dyn std::error::Error>> {
+        load_table(path, &mut Vec::new(), &mut HashSet::new())
+    }
+
+    #[test]
+    fn merge_tables_merges_tables_appends_arrays_and_replaces_values() {
+        let mut base: toml::Table =
+            toml::from_str("name = \"base\"\nlist = [1]\n[section]\nkept = true\nchanged = 1").unwrap();
+        let overlay: toml::Table =
+            toml::from_str("name = \"overlay\"\nlist = [2]\n[section]\nchanged = 2").unwrap();
+        merge_tables(&mut base, overlay);
+
+        let expected: toml::Table =
+            toml::from_str("name = \"overlay\"\nlist = [1, 2]\n[section]\nkept = true\nchanged = 2")
+                .unwrap();
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn includes_are_merged_relative_to_the_including_file() {
+        let dir = config_dir(
+            "include",
+            &[
+                ("main.toml", "include = [\"tenants/a.toml\"]\nlimit = 1\nhosts = [\"main\"]"),
+                ("tenants/a.toml", "limit = 2\nhosts = [\"a\"]"),
+            ],
+        );
+        let table = load(&dir.join("main.toml")).unwrap();
+
+        let expected: toml::Table = toml::from_str("limit = 2\nhosts = [\"main\", \"a\"]").unwrap();
+        assert_eq!(table, expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn shared_include_is_merged_once() {
+        let dir = config_dir(
+            "diamond",
+            &[
+                ("main.toml", "include = [\"a.toml\", \"b.toml\"]"),
+                ("a.toml", "include = [\"common.toml\"]\nhosts = [\"a\"]"),
+                ("b.toml", "include = [\"common.toml\"]\nhosts = [\"b\"]"),
+                ("common.toml", "hosts = [\"common\"]"),
+            ],
+        );
+        let table = load(&dir.join("main.toml")).unwrap();
+
+        let expected: toml::Table = toml::from_str("hosts = [\"a\", \"common\", \"b\"]").unwrap();
+        assert_eq!(table, expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn include_cycle_is_an_error() {
+        let dir = config_dir(
+            "cycle",
+            &[("a.toml", "include = [\"b.toml\"]"), ("b.toml", "include = [\"a.toml\"]")],
+        );
+        let err = load(&dir.join("a.toml")).unwrap_err();
+
+        assert!(err.to_string().contains("circular config include"), "{err}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn timeout_config() -> Config {
+        test_config(
+            r#"
+            limits.connect_timeout_secs = 3
+
+            [[timeout_override]]
+            path = "/upload"
+            methods = ["post"]
+            timeout_secs = 120
+            max_response_ms = 5000
+
+            [[timeout_override]]
+            path = "/"
+            timeout_secs = 10
+            connect_timeout_secs = 1
+
+            [[aggregate]]
+            path = "/dashboard"
+            timeout_secs = 5
+            request = []
+            "#,
+        )
+    }
+
+    #[test]
+    fn timeouts_fall_back_to_limits() {
+        let config = test_config("limits.connect_timeout_secs = 3");
+        let timeouts = config.timeouts("GET", "/api", None);
+        assert_eq!(
+            timeouts,
+            Timeouts { total_secs: 30, connect_secs: Some(3), max_response_ms: None }
+        );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn first_matching_override_wins_field_by_field() {
+        let config = timeout_config();
+        assert_eq!(
+            config.timeouts("POST", "/upload/big", None),
+            Timeouts { total_secs: 120, connect_secs: Some(3), max_response_ms: Some(5000) }
+        );
+        // The method doesn't match the first rule, so the catch-all applies
+        assert_eq!(
+            config.timeouts("GET", "/upload/big", None),
+            Timeouts { total_secs: 10, connect_secs: Some(1), max_response_ms: None }
+        );
+    }
+
+    #[test]
+    fn aggregate_route_takes_precedence_over_overrides() {
+        let config = timeout_config();
+        let route = config.aggregate_for_path("/dashboard");
+        assert_eq!(
+            config.timeouts("GET", "/dashboard", route),
+            Timeouts { total_secs: 5, connect_secs: Some(1), max_response_ms: None }
+        );
+    }
+
+    #[test]
+    fn replay_missing_status_must_be_400_or_401() {
+        let config = test_config("[replay_protection]\npaths = [\"/pay\"]\nmissing_status = 401");
+        assert!(config.validate().is_ok());
+
+        let config = test_config("[replay_protection]\npaths = [\"/pay\"]\nmissing_status = 999");
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("missing_status"), "{err}");
+    }
+
+    #[test]
+    fn challenge_must_come_before_the_ban() {
+        let challenge = |after: u32| {
+            test_config(&format!(
+                "[rate_limit.challenge]\nsecret = \"s\"\nafter_violations = {after}"
+            ))
+        };
+        assert!(challenge(3).validate().is_ok());
+        assert!(challenge(0).validate().is_err());
+        let err = challenge(MAX_VIOLATIONS).validate().unwrap_err();
+        assert!(err.contains("after_violations"), "{err}");
+    }
+
+    #[test]
+    fn zero_concurrency_cap_is_rejected() {
+        assert!(test_config("rate_limit.max_concurrent_per_user = 1").validate().is_ok());
+        let err = test_config("rate_limit.max_concurrent_per_user = 0").validate().unwrap_err();
+        assert!(err.contains("max_concurrent_per_user"), "{err}");
+    }
+
+    #[test]
+    fn default_content_type_must_be_a_media_type() {
+        assert!(test_config("proxy.default_content_type = \"text/html\"").validate().is_ok());
+        assert!(test_config("proxy.default_content_type = \"html\"").validate().is_err());
+        let err = test_config("proxy.default_content_type = \"text/plain\\n\"").validate().unwrap_err();
+        assert!(err.contains("default_content_type"), "{err}");
+    }
+
+    #[test]
+    fn forwarded_proto_uses_the_first_matching_override() {
+        let config = test_config(
+            r#"
+            proxy.forwarded_proto = "http"
+
+            [[forwarded_proto_override]]
+            path = "/internal/health"
+            proto = "https"
+
+            [[forwarded_proto_override]]
+            path = "/internal"
+            proto = "wss"
+            "#,
+        );
+        assert_eq!(config.forwarded_proto_for_path("/internal/health/live"), "https");
+        assert_eq!(config.forwarded_proto_for_path("/internal/jobs"), "wss");
+        assert_eq!(config.forwarded_proto_for_path("/api"), "http");
+    }
+
+    #[test]
+    fn forwarded_proto_defaults_to_https() {
+        assert_eq!(test_config("").forwarded_proto_for_path("/api"), "https");
+    }
+}
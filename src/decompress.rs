@@ -0,0 +1,169 @@
+use bytes::Bytes;
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+use http_body_util::Full;
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH};
+use hyper::{HeaderMap, Response, StatusCode};
+use std::io::Read;
+use tracing::{debug, warn};
+
+use crate::config::LimitsConfig;
+
+/// Enforce `limits.allowed_request_encodings` and, with
+/// `limits.max_decompressed_size` set, inflate gzip/deflate request
+/// bodies within that bound so a tiny compressed upload can't expand
+/// into a huge one at the backend. The inflated body is forwarded and
+/// Content-Encoding removed.
+///
+/// Returns Some(Response) with 400 for a disallowed, stacked or corrupt
+/// encoding, 415 for one that can't be inflated while a bound is set,
+/// and 413 when the inflated body exceeds the bound.
+pub fn check_request_body(
+    headers: &mut HeaderMap,
+    body: &mut Bytes,
+    limits: &LimitsConfig,
+) -> Option<Response<Full<Bytes>>> {
+    let encodings: Vec<String> = headers
+        .get_all(CONTENT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty() && v != "identity")
+        .collect();
+
+    if encodings.is_empty() {
+        return None;
+    }
+
+    if let Some(allowed) = &limits.allowed_request_encodings {
+        if let Some(denied) = encodings
+            .iter()
+            .find(|e| !allowed.iter().any(|a| a.eq_ignore_ascii_case(e)))
+        {
+            warn!(encoding = %denied, "Request Content-Encoding not allowed");
+            return Some(status_response(StatusCode::BAD_REQUEST));
+        }
+    }
+
+    let max = limits.max_decompressed_size?;
+
+    // Layered codings are the classic way to multiply a bomb's ratio.
+    if encodings.len() > 1 {
+        warn!(encodings = ?encodings, "Stacked request Content-Encoding rejected");
+        return Some(status_response(StatusCode::BAD_REQUEST));
+    }
+
+    let decoder: Box<dyn Read + '_> = match encodings[0].as_str() {
+        // A gzip stream may hold several members; all of them are the body.
+        "gzip" | "x-gzip" => Box::new(MultiGzDecoder::new(&body[..])),
+        "deflate" => Box::new(ZlibDecoder::new(&body[..])),
+        // Can't be measured, so it can't be let through under a bound.
+        other => {
+            warn!(encoding = %other, "Request Content-Encoding can't be inflated");
+            return Some(status_response(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        }
+    };
+
+    let mut inflated = Vec::new();
+    if let Err(e) = decoder.take(max + 1).read_to_end(&mut inflated) {
+        warn!(error = %e, "Corrupt compressed request body");
+        return Some(status_response(StatusCode::BAD_REQUEST));
+    }
+    if inflated.len() as u64 > max {
+        warn!(
+            compressed = body.len(),
+            max_decompressed_size = max,
+            "Compressed request body exceeds decompressed size limit"
+        );
+        return Some(status_response(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    debug!(compressed = body.len(), inflated = inflated.len(), "Inflated request body");
+    headers.remove(CONTENT_ENCODING);
+    headers.remove(CONTENT_LENGTH);
+    *body = Bytes::from(inflated);
+    None
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Length", "0")
+        .body(Full::new(Bytes::new()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn gzip_headers(encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, encoding.parse().unwrap());
+        headers
+    }
+
+    fn limits(max_decompressed_size: u64) -> LimitsConfig {
+        test_config(&format!("limits.max_decompressed_size = {max_decompressed_size}")).limits
+    }
+
+    #[test]
+    fn compression_bomb_is_rejected() {
+        let mut body = Bytes::from(gzip(&vec![0u8; 10 * 1024 * 1024]));
+        assert!(body.len() < 64 * 1024);
+
+        let response = check_request_body(&mut gzip_headers("gzip"), &mut body, &limits(1024 * 1024));
+        assert_eq!(response.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn gzip_body_is_inflated() {
+        let mut headers = gzip_headers("gzip");
+        let mut body = Bytes::from(gzip(b"{\"a\":1}"));
+
+        assert!(check_request_body(&mut headers, &mut body, &limits(1024)).is_none());
+        assert_eq!(&body[..], b"{\"a\":1}");
+        assert!(!headers.contains_key(CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn every_gzip_member_is_inflated() {
+        let mut members = gzip(b"first,");
+        members.extend(gzip(b"second"));
+        let mut body = Bytes::from(members);
+
+        assert!(check_request_body(&mut gzip_headers("gzip"), &mut body, &limits(1024)).is_none());
+        assert_eq!(&body[..], b"first,second");
+    }
+
+    #[test]
+    fn stacked_and_corrupt_encodings_are_rejected() {
+        let mut body = Bytes::from(gzip(b"x"));
+        let response = check_request_body(&mut gzip_headers("gzip, gzip"), &mut body, &limits(1024));
+        assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+
+        let mut body = Bytes::from_static(b"not gzip");
+        let response = check_request_body(&mut gzip_headers("gzip"), &mut body, &limits(1024));
+        assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn encodings_that_cant_be_inflated_are_rejected_under_a_bound() {
+        let mut body = Bytes::from_static(b"\x1b\x00\x00\x00brotli");
+        let response = check_request_body(&mut gzip_headers("br"), &mut body, &limits(1024));
+        assert_eq!(response.unwrap().status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let unbounded = test_config("").limits;
+        assert!(check_request_body(&mut gzip_headers("br"), &mut body, &unbounded).is_none());
+    }
+}
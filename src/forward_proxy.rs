@@ -0,0 +1,146 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+use crate::config::ForwardProxyConfig;
+
+pub fn is_connect<B>(req: &Request<B>) -> bool {
+    req.method() == Method::CONNECT
+}
+
+/// Answer a CONNECT for `[forward_proxy]`: tunnel to the requested
+/// `host:port` if it's allowlisted, refuse with 403 otherwise. The
+/// allowlist is required, so this can never act as an open proxy.
+///
+/// The destination is connected before answering, so an unreachable
+/// one gets 502 (504 on connect timeout) rather than a dead tunnel.
+pub async fn handle(
+    mut req: Request<Incoming>,
+    config: &ForwardProxyConfig,
+    connect_timeout: Option<Duration>,
+) -> Response<Full<Bytes>> {
+    let destination = match req.uri().authority() {
+        Some(authority) if authority.port().is_some() => authority.as_str().to_ascii_lowercase(),
+        _ => return status_response(StatusCode::BAD_REQUEST),
+    };
+
+    if !config
+        .allowed_destinations
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(&destination))
+    {
+        warn!(destination = %destination, "CONNECT to destination not allowed");
+        return status_response(StatusCode::FORBIDDEN);
+    }
+
+    let connect = TcpStream::connect(destination.as_str());
+    let connected = match connect_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connect).await,
+        None => Ok(connect.await),
+    };
+    let mut target = match connected {
+        Ok(Ok(target)) => target,
+        Ok(Err(e)) => {
+            warn!(destination = %destination, error = %e, "CONNECT destination unreachable");
+            return status_response(StatusCode::BAD_GATEWAY);
+        }
+        Err(_) => {
+            warn!(destination = %destination, "CONNECT destination timed out");
+            return status_response(StatusCode::GATEWAY_TIMEOUT);
+        }
+    };
+
+    // hyper hands over the client connection once the 200 is sent
+    let upgrade = hyper::upgrade::on(&mut req);
+    info!(destination = %destination, "CONNECT tunnel opened");
+    tokio::spawn(async move {
+        let mut client = match upgrade.await {
+            Ok(upgraded) => TokioIo::new(upgraded),
+            Err(e) => {
+                warn!(error = %e, "CONNECT upgrade failed");
+                return;
+            }
+        };
+
+        match tokio::io::copy_bidirectional(&mut client, &mut target).await {
+            Ok((sent, received)) => {
+                info!(destination = %destination, sent, received, "CONNECT tunnel closed")
+            }
+            Err(e) => warn!(destination = %destination, error = %e, "CONNECT tunnel error"),
+        }
+    });
+
+    Response::new(Full::new(Bytes::new()))
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Length", "0")
+        .body(Full::new(Bytes::new()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use std::convert::Infallible;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// Send a CONNECT for `destination` through `handle` with only
+    /// `allowed` on the allowlist. Returns the response head and the
+    /// client end of the connection, positioned after the head.
+    async fn connect(allowed: String, destination: &str) -> (String, DuplexStream) {
+        let config = ForwardProxyConfig { allowed_destinations: vec![allowed] };
+        let (mut client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let config = config.clone();
+                async move { Ok::<_, Infallible>(handle(req, &config, None).await) }
+            });
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(server), service)
+                .with_upgrades()
+                .await;
+        });
+
+        let request = format!("CONNECT {destination} HTTP/1.1\r\nHost: {destination}\r\n\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await.unwrap());
+        }
+        (String::from_utf8(head).unwrap(), client)
+    }
+
+    #[tokio::test]
+    async fn allowlisted_destination_is_tunnelled() {
+        let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination = echo.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = echo.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.into_split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+
+        let (head, mut tunnel) = connect(destination.clone(), &destination).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+        tunnel.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        tunnel.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    #[tokio::test]
+    async fn other_destinations_are_forbidden() {
+        let (head, _) = connect("127.0.0.1:8443".to_string(), "127.0.0.1:22").await;
+        assert!(head.starts_with("HTTP/1.1 403"), "{head}");
+    }
+}
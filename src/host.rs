@@ -0,0 +1,183 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::{HeaderValue, HOST};
+use hyper::{Request, Response, StatusCode};
+use tracing::{error, warn};
+
+use crate::config::{InvalidHostAction, ServerConfig};
+
+/// Host header allowlist checked before anything reaches the backend,
+/// so a spoofed Host can't poison Django's URL generation or caches.
+pub struct HostCheck {
+    allowed: Vec<String>,
+    action: InvalidHostAction,
+    canonical: Option<HeaderValue>,
+    exempt: Vec<String>,
+}
+
+impl HostCheck {
+    pub fn new(config: &ServerConfig) -> Self {
+        let canonical = config.canonical_host.as_ref().map(|host| {
+            HeaderValue::from_str(host).expect("server.canonical_host is not a valid header value")
+        });
+
+        if config.invalid_host_action == InvalidHostAction::Rewrite && canonical.is_none() {
+            error!("invalid_host_action = \"rewrite\" without canonical_host, rejecting instead");
+        }
+
+        Self {
+            allowed: config
+                .allowed_hosts
+                .iter()
+                .map(|h| h.to_ascii_lowercase())
+                .collect(),
+            action: config.invalid_host_action,
+            canonical,
+            exempt: config.host_check_exempt.clone(),
+        }
+    }
+
+    /// Check the request's Host against the allowlist.
+    /// Returns Some(Response) with 400 if rejected. In rewrite mode the
+    /// Host header is replaced with the canonical host instead.
+    pub fn check<B>(&self, req: &mut Request<B>) -> Option<Response<Full<Bytes>>> {
+        if self.allowed.is_empty() {
+            return None;
+        }
+
+        let path = req.uri().path();
+        if self.exempt.iter().any(|prefix| path.starts_with(prefix.as_str())) {
+            return None;
+        }
+
+        let host = req
+            .headers()
+            .get(HOST)
+            .and_then(|v| v.to_str().ok())
+            .or_else(|| req.uri().host())
+            .map(strip_port)
+            .map(|h| h.to_ascii_lowercase());
+
+        if let Some(host) = &host {
+            if self.is_allowed(host) {
+                return None;
+            }
+        }
+
+        match (&self.action, &self.canonical) {
+            (InvalidHostAction::Rewrite, Some(canonical)) => {
+                warn!(host = ?host, "Host not allowed, rewriting to canonical host");
+                req.headers_mut().insert(HOST, canonical.clone());
+                None
+            }
+            _ => {
+                warn!(host = ?host, "Host not allowed, rejecting");
+                Some(
+                    Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .header("Content-Length", "0")
+                        .body(Full::new(Bytes::new()))
+                        .unwrap(),
+                )
+            }
+        }
+    }
+
+    fn is_allowed(&self, host: &str) -> bool {
+        self.allowed.iter().any(|pattern| {
+            if pattern == "*" {
+                true
+            } else if let Some(suffix) = pattern.strip_prefix("*.") {
+                host.len() > suffix.len() + 1
+                    && host.ends_with(suffix)
+                    && host.as_bytes()[host.len() - suffix.len() - 1] == b'.'
+            } else if let Some(domain) = pattern.strip_prefix('.') {
+                host == domain || host.ends_with(pattern.as_str())
+            } else {
+                host == pattern
+            }
+        })
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    // IPv6 literal: [::1]:8080
+    if let Some(end) = host.find(']') {
+        return &host[..=end];
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn host_check(overrides: &str) -> HostCheck {
+        HostCheck::new(&test_config(overrides).server)
+    }
+
+    #[test]
+    fn patterns_match_exact_wildcard_and_domain_hosts() {
+        let check = host_check(r#"server.allowed_hosts = ["example.com", "*.api.example.com", ".example.org"]"#);
+        assert!(check.is_allowed("example.com"));
+        assert!(!check.is_allowed("www.example.com"));
+
+        assert!(check.is_allowed("v1.api.example.com"));
+        assert!(!check.is_allowed("api.example.com"));
+        assert!(!check.is_allowed("evilapi.example.com"));
+
+        assert!(check.is_allowed("example.org"));
+        assert!(check.is_allowed("www.example.org"));
+        assert!(!check.is_allowed("badexample.org"));
+
+        assert!(host_check(r#"server.allowed_hosts = ["*"]"#).is_allowed("anything.test"));
+    }
+
+    #[test]
+    fn ports_are_ignored_and_case_folded() {
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+        assert_eq!(strip_port("example.com"), "example.com");
+
+        let check = host_check(r#"server.allowed_hosts = ["Example.com"]"#);
+        let mut req = Request::get("/").header(HOST, "EXAMPLE.com:443").body(()).unwrap();
+        assert!(check.check(&mut req).is_none());
+    }
+
+    #[test]
+    fn disallowed_host_is_rejected_or_rewritten() {
+        let mut req = Request::get("/").header(HOST, "evil.test").body(()).unwrap();
+        let response = host_check(r#"server.allowed_hosts = ["example.com"]"#).check(&mut req).unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let check = host_check(
+            r#"
+            server.allowed_hosts = ["example.com"]
+            server.invalid_host_action = "rewrite"
+            server.canonical_host = "example.com"
+            "#,
+        );
+        assert!(check.check(&mut req).is_none());
+        assert_eq!(req.headers()[HOST], "example.com");
+    }
+
+    #[test]
+    fn empty_allowlist_and_exempt_paths_skip_the_check() {
+        let mut req = Request::get("/").header(HOST, "evil.test").body(()).unwrap();
+        assert!(host_check("").check(&mut req).is_none());
+
+        let check = host_check(
+            r#"
+            server.allowed_hosts = ["example.com"]
+            server.host_check_exempt = ["/healthz"]
+            "#,
+        );
+        let mut req = Request::get("/healthz").header(HOST, "10.0.0.5:8000").body(()).unwrap();
+        assert!(check.check(&mut req).is_none());
+    }
+}
@@ -0,0 +1,412 @@
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use hyper::{HeaderMap, Response, StatusCode};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{error, warn};
+
+use crate::config::JwtConfig;
+
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Minimum gap between fetch attempts, whether the last one failed or
+/// a token named a kid the current set doesn't have.
+const JWKS_MIN_REFETCH: Duration = Duration::from_secs(30);
+
+/// Validates bearer tokens on protected paths and forwards chosen claims
+/// as headers, so the upstream doesn't have to parse the token again.
+pub struct JwtAuth {
+    paths: Vec<String>,
+    secret: Option<DecodingKey>,
+    jwks_url: Option<String>,
+    jwks_refresh: Duration,
+    jwks: RwLock<JwksCache>,
+    /// Held while fetching, so only one request goes to the JWKS endpoint.
+    jwks_fetch: Mutex<()>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway_secs: u64,
+    claims: Vec<(String, HeaderName)>,
+}
+
+impl JwtAuth {
+    pub fn new(config: &JwtConfig) -> Self {
+        assert!(
+            config.secret.is_some() != config.jwks_url.is_some(),
+            "jwt needs exactly one of secret or jwks_url"
+        );
+
+        let claims = config
+            .claim
+            .iter()
+            .map(|claim| {
+                let header = HeaderName::from_bytes(claim.header.as_bytes())
+                    .expect("jwt.claim header is not a valid header name");
+                (claim.name.clone(), header)
+            })
+            .collect();
+
+        Self {
+            paths: config.paths.clone(),
+            secret: config.secret.as_ref().map(|s| DecodingKey::from_secret(s.as_bytes())),
+            jwks_url: config.jwks_url.clone(),
+            jwks_refresh: Duration::from_secs(config.jwks_refresh_secs),
+            jwks: RwLock::new(JwksCache::default()),
+            jwks_fetch: Mutex::new(()),
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            leeway_secs: config.leeway_secs,
+            claims,
+        }
+    }
+
+    /// Strip client-supplied claim headers, then on protected paths
+    /// validate the bearer token and add its claims as headers.
+    /// Returns Some(Response) with 401 if the token is missing or invalid.
+    pub async fn check(&self, path: &str, headers: &mut HeaderMap) -> Option<Response<Full<Bytes>>> {
+        for (_, header) in &self.claims {
+            headers.remove(header);
+        }
+
+        if !self.paths.iter().any(|prefix| path.starts_with(prefix.as_str())) {
+            return None;
+        }
+
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim);
+        let Some(token) = token else {
+            return Some(unauthorized());
+        };
+
+        let claims = match self.validate(token).await {
+            Ok(claims) => claims,
+            Err(reason) => {
+                warn!(path = path, reason = %reason, "Rejected JWT");
+                return Some(unauthorized());
+            }
+        };
+
+        for (name, header) in &self.claims {
+            let value = match claims.get(name) {
+                Some(Value::String(s)) => s.clone(),
+                Some(Value::Array(items)) => items
+                    .iter()
+                    .map(|item| item.as_str().map(str::to_string).unwrap_or_else(|| item.to_string()))
+                    .collect::<Vec<_>>()
+                    .join(","),
+                Some(Value::Null) | None => continue,
+                Some(other) => other.to_string(),
+            };
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(header.clone(), value);
+            }
+        }
+
+        None
+    }
+
+    async fn validate(&self, token: &str) -> Result<Map<String, Value>, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+        let is_hmac = matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512);
+
+        // Only accept HMAC tokens with a shared secret and asymmetric ones
+        // with the key set, so a public key can never be used as a secret
+        let key = match &self.secret {
+            Some(secret) if is_hmac => secret.clone(),
+            Some(_) => return Err(format!("algorithm {:?} not allowed", header.alg)),
+            None if is_hmac => return Err(format!("algorithm {:?} not allowed", header.alg)),
+            None => self.jwks_key(header.kid.as_deref()).await?,
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.leeway_secs;
+        validation.validate_nbf = true;
+        validation.validate_aud = self.audience.is_some();
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+
+        jsonwebtoken::decode::<Map<String, Value>>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| e.to_string())
+    }
+
+    /// The key for `kid` from the cached key set, fetching it again once
+    /// it's older than jwks_refresh_secs. A failed fetch keeps the old set.
+    /// An unknown kid triggers one early fetch in case the keys rotated,
+    /// at most once per JWKS_MIN_REFETCH.
+    async fn jwks_key(&self, kid: Option<&str>) -> Result<DecodingKey, String> {
+        let refresh = self.jwks_refresh;
+        self.refresh_jwks(|cache| cache.expired(refresh)).await;
+
+        if let Some(key) = self.jwks.read().await.key(kid) {
+            return key;
+        }
+        if self.refresh_jwks(JwksCache::may_refetch).await {
+            if let Some(key) = self.jwks.read().await.key(kid) {
+                return key;
+            }
+        }
+
+        if self.jwks.read().await.set.is_none() {
+            return Err("no key set available".to_string());
+        }
+        Err(format!("no key for kid {kid:?}"))
+    }
+
+    /// Fetch the key set if `due` says so. The cache stays readable while
+    /// the fetch runs; callers that find a fetch already underway wait for
+    /// it and then re-check `due` instead of fetching again. Returns
+    /// whether a fetch succeeded.
+    async fn refresh_jwks(&self, due: impl Fn(&JwksCache) -> bool) -> bool {
+        if !due(&*self.jwks.read().await) {
+            return false;
+        }
+        let _fetching = self.jwks_fetch.lock().await;
+        if !due(&*self.jwks.read().await) {
+            return false;
+        }
+
+        let fetched = self.fetch_jwks().await;
+        let mut cache = self.jwks.write().await;
+        cache.attempted = Some(Instant::now());
+        match fetched {
+            Ok(set) => {
+                cache.set = Some(set);
+                cache.fetched = cache.attempted;
+                true
+            }
+            Err(e) => {
+                error!(error = %e, "Failed to fetch JWKS");
+                false
+            }
+        }
+    }
+
+    async fn fetch_jwks(&self) -> Result<JwkSet, String> {
+        let url = self.jwks_url.as_deref().unwrap_or_default();
+        let uri: hyper::Uri = url.parse().map_err(|e| format!("{e}"))?;
+
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let client = Client::builder(TokioExecutor::new()).build::<_, Empty<Bytes>>(connector);
+
+        let fetch = async {
+            let response = client.get(uri).await.map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("status {}", response.status()));
+            }
+            let body = response.into_body().collect().await.map_err(|e| e.to_string())?;
+            serde_json::from_slice(&body.to_bytes()).map_err(|e| e.to_string())
+        };
+
+        tokio::time::timeout(JWKS_FETCH_TIMEOUT, fetch)
+            .await
+            .map_err(|_| "timed out".to_string())?
+    }
+}
+
+#[derive(Default)]
+struct JwksCache {
+    set: Option<JwkSet>,
+    /// Last successful fetch.
+    fetched: Option<Instant>,
+    /// Last fetch attempt, successful or not.
+    attempted: Option<Instant>,
+}
+
+impl JwksCache {
+    /// The set is older than `refresh` (or missing) and the last attempt
+    /// was long enough ago to try again.
+    fn expired(&self, refresh: Duration) -> bool {
+        let stale = !matches!(self.fetched, Some(at) if at.elapsed() < refresh);
+        stale && self.may_refetch()
+    }
+
+    fn may_refetch(&self) -> bool {
+        !matches!(self.attempted, Some(at) if at.elapsed() < JWKS_MIN_REFETCH)
+    }
+
+    fn key(&self, kid: Option<&str>) -> Option<Result<DecodingKey, String>> {
+        let set = self.set.as_ref()?;
+        let jwk = match kid {
+            Some(kid) => set.find(kid),
+            None if set.keys.len() == 1 => set.keys.first(),
+            None => None,
+        }?;
+        Some(DecodingKey::from_jwk(jwk).map_err(|e| e.to_string()))
+    }
+}
+
+fn unauthorized() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header("WWW-Authenticate", "Bearer")
+        .header("Content-Length", "0")
+        .body(Full::new(Bytes::new()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::JwtClaimHeader;
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::Request;
+    use hyper_util::rt::TokioIo;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const SECRET: &str = "test-secret";
+    /// `{"alg":"RS256","kid":"rotated"}` with an empty payload; only the
+    /// header is read before the key lookup.
+    const UNKNOWN_KID_TOKEN: &str = "eyJhbGciOiJSUzI1NiIsImtpZCI6InJvdGF0ZWQifQ.e30.c2ln";
+
+    fn jwt_config(secret: Option<&str>, jwks_url: Option<String>) -> JwtConfig {
+        JwtConfig {
+            paths: vec!["/api".to_string()],
+            secret: secret.map(str::to_string),
+            jwks_url,
+            jwks_refresh_secs: 300,
+            issuer: None,
+            audience: None,
+            leeway_secs: 0,
+            claim: vec![JwtClaimHeader {
+                name: "sub".to_string(),
+                header: "x-user".to_string(),
+            }],
+        }
+    }
+
+    fn hs256_token(exp_offset_secs: i64) -> String {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let claims = json!({ "sub": "alice", "exp": now + exp_offset_secs });
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+        headers
+    }
+
+    /// Serves an empty key set from /jwks and 500 from /broken, counting
+    /// requests.
+    async fn jwks_upstream() -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let counter = counter.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let status = match req.uri().path() {
+                        "/jwks" => StatusCode::OK,
+                        _ => StatusCode::INTERNAL_SERVER_ERROR,
+                    };
+                    let response = Response::builder()
+                        .status(status)
+                        .body(Full::new(Bytes::from_static(b"{\"keys\":[]}")))
+                        .unwrap();
+                    async move { Ok::<_, Infallible>(response) }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        (format!("http://{addr}"), hits)
+    }
+
+    #[tokio::test]
+    async fn valid_token_passes_and_forwards_claims() {
+        let auth = JwtAuth::new(&jwt_config(Some(SECRET), None));
+        let mut headers = bearer(&hs256_token(60));
+        headers.insert("x-user", "mallory".parse().unwrap());
+
+        assert!(auth.check("/api/orders", &mut headers).await.is_none());
+        assert_eq!(headers["x-user"], "alice");
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_rejected() {
+        let auth = JwtAuth::new(&jwt_config(Some(SECRET), None));
+        let mut headers = bearer(&hs256_token(-60));
+
+        let response = auth.check("/api/orders", &mut headers).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(headers.get("x-user").is_none());
+    }
+
+    #[tokio::test]
+    async fn missing_token_is_rejected_only_on_protected_paths() {
+        let auth = JwtAuth::new(&jwt_config(Some(SECRET), None));
+        let mut headers = HeaderMap::new();
+        headers.insert("x-user", "mallory".parse().unwrap());
+
+        assert!(auth.check("/public", &mut headers).await.is_none());
+        assert!(headers.get("x-user").is_none());
+        let response = auth.check("/api/orders", &mut headers).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn unknown_kid_refetches_at_most_once_per_interval() {
+        let (base, hits) = jwks_upstream().await;
+        let auth = JwtAuth::new(&jwt_config(None, Some(format!("{base}/jwks"))));
+
+        assert!(auth.validate(UNKNOWN_KID_TOKEN).await.is_err());
+        assert!(auth.validate(UNKNOWN_KID_TOKEN).await.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        auth.jwks.write().await.attempted = Some(Instant::now() - 2 * JWKS_MIN_REFETCH);
+        assert!(auth.validate(UNKNOWN_KID_TOKEN).await.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_fetch_backs_off() {
+        let (base, hits) = jwks_upstream().await;
+        let auth = Arc::new(JwtAuth::new(&jwt_config(None, Some(format!("{base}/broken")))));
+
+        let attempts: Vec<_> = (0..8)
+            .map(|_| {
+                let auth = auth.clone();
+                tokio::spawn(async move { auth.validate(UNKNOWN_KID_TOKEN).await })
+            })
+            .collect();
+        for attempt in attempts {
+            let err = attempt.await.unwrap().unwrap_err();
+            assert_eq!(err, "no key set available");
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+}
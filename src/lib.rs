@@ -1,5 +1,20 @@
+pub mod admin;
+pub mod aggregate;
+pub mod algorithm;
+pub mod challenge;
+pub mod client_ip;
 pub mod config;
+pub mod decompress;
 pub mod filter;
+pub mod forward_proxy;
+pub mod host;
+pub mod jwt;
+pub mod loadshed;
 pub mod proxy;
+pub mod query;
 pub mod ratelimit;
+pub mod replay;
+pub mod rewrite;
+pub mod session;
+pub mod signing;
 pub mod tcp;
@@ -0,0 +1,114 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{Response, StatusCode};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::warn;
+
+use crate::config::LoadShedConfig;
+
+/// Sheds requests with 503 once too many are in flight in the proxy
+/// itself, so it stays responsive instead of queueing work it can't
+/// finish. Requests under critical_paths are always admitted.
+pub struct LoadShedder {
+    max_active_requests: usize,
+    critical_paths: Vec<String>,
+    active: AtomicUsize,
+}
+
+/// Counts one in-flight request; released on drop.
+pub struct ActiveGuard<'a> {
+    active: &'a AtomicUsize,
+}
+
+impl Drop for ActiveGuard<'_> {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl LoadShedder {
+    pub fn new(config: &LoadShedConfig) -> Self {
+        Self {
+            max_active_requests: config.max_active_requests,
+            critical_paths: config.critical_paths.clone(),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Admit the request, or None when it should be shed.
+    pub fn admit(&self, path: &str) -> Option<ActiveGuard<'_>> {
+        let active = self.active.fetch_add(1, Ordering::Relaxed);
+        let guard = ActiveGuard { active: &self.active };
+        if active < self.max_active_requests
+            || self.critical_paths.iter().any(|prefix| path.starts_with(prefix.as_str()))
+        {
+            return Some(guard);
+        }
+
+        warn!(path = path, active = active, "Shedding request under load");
+        None
+    }
+
+    pub fn shed_response() -> Response<Full<Bytes>> {
+        let mut response = status_response(StatusCode::SERVICE_UNAVAILABLE);
+        response.headers_mut().insert("Retry-After", "1".parse().unwrap());
+        response
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Length", "0")
+        .body(Full::new(Bytes::new()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shedder(max_active_requests: usize) -> LoadShedder {
+        LoadShedder::new(&LoadShedConfig {
+            max_active_requests,
+            critical_paths: vec!["/health".to_string()],
+        })
+    }
+
+    #[test]
+    fn sheds_past_the_limit_and_recovers_when_requests_finish() {
+        let shedder = shedder(2);
+        let first = shedder.admit("/api").unwrap();
+        let _second = shedder.admit("/api").unwrap();
+        assert!(shedder.admit("/api").is_none());
+
+        drop(first);
+        assert!(shedder.admit("/api").is_some());
+    }
+
+    #[test]
+    fn critical_paths_are_always_admitted() {
+        let shedder = shedder(1);
+        let _busy = shedder.admit("/api").unwrap();
+        assert!(shedder.admit("/api").is_none());
+        assert!(shedder.admit("/health/live").is_some());
+    }
+
+    #[test]
+    fn shed_requests_release_their_slot() {
+        let shedder = shedder(1);
+        let busy = shedder.admit("/api").unwrap();
+        for _ in 0..3 {
+            assert!(shedder.admit("/api").is_none());
+        }
+        drop(busy);
+        assert_eq!(shedder.active.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn shed_response_asks_to_retry() {
+        let response = LoadShedder::shed_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["Retry-After"], "1");
+    }
+}
@@ -1,37 +1,72 @@
-mod config;
-mod filter;
-mod proxy;
-mod ratelimit;
-
 use bytes::Bytes;
+use governor::Quota;
 use http_body_util::Full;
 use hyper::body::Incoming;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
+use hyper::header::{HeaderValue, CACHE_CONTROL};
 use hyper::{Request, Response};
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioIo, TokioTimer};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
 use tracing::{error, info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter};
 
-use config::Config;
-use filter::Filter;
-use ratelimit::RateLimit;
+use wardent::config::{Config, Timeouts};
+use wardent::filter::Filter;
+use wardent::host::HostCheck;
+use wardent::jwt::JwtAuth;
+use wardent::loadshed::LoadShedder;
+use wardent::query::QuerySchema;
+use wardent::ratelimit::{ConnectionRate, RateLimit};
+use wardent::replay::ReplayGuard;
+use wardent::session::SessionOrdering;
+use wardent::admin::LogFilterHandle;
+use wardent::{admin, aggregate, client_ip, forward_proxy, proxy, query, tcp};
 
 struct AppState {
     config: Config,
     filter: Filter,
+    host_check: HostCheck,
     rate_limiter: RateLimit,
+    query_schema: QuerySchema,
+    session_ordering: Option<SessionOrdering>,
+    replay_guard: Option<ReplayGuard>,
+    load_shedder: Option<LoadShedder>,
+    jwt: Option<JwtAuth>,
+    log_filter: LogFilterHandle,
+}
+
+impl AppState {
+    fn new(config: Config, log_filter: LogFilterHandle) -> Self {
+        Self {
+            filter: Filter::new(&config.filter),
+            host_check: HostCheck::new(&config.server),
+            rate_limiter: RateLimit::new(&config.rate_limit),
+            query_schema: QuerySchema::new(&config.query_schema),
+            session_ordering: config.session_ordering.as_ref().map(SessionOrdering::new),
+            replay_guard: config.replay_protection.as_ref().map(ReplayGuard::new),
+            load_shedder: config.server.load_shed.as_ref().map(LoadShedder::new),
+            jwt: config.jwt.as_ref().map(JwtAuth::new),
+            log_filter,
+            config,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "wardent=info".into()),
-        )
+    let (log_filter, log_filter_handle) = reload::Layer::new(
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| "wardent=info".into()),
+    );
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(tracing_subscriber::fmt::layer())
         .init();
 
     let config_path = std::env::args()
@@ -58,11 +93,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         );
     }
 
-    let state = Arc::new(AppState {
-        filter: Filter::new(&config.filter),
-        rate_limiter: RateLimit::new(&config.rate_limit),
-        config,
-    });
+    let state = Arc::new(AppState::new(config, log_filter_handle));
 
     let cleanup_state = state.clone();
     tokio::spawn(async move {
@@ -70,13 +101,58 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         loop {
             interval.tick().await;
             cleanup_state.rate_limiter.cleanup();
+            if let Some(replay_guard) = &cleanup_state.replay_guard {
+                replay_guard.cleanup();
+            }
         }
     });
 
+    start_admin(&state).await?;
+
     let addr: SocketAddr = state.config.server.listen_addr.parse()?;
-    let listener = TcpListener::bind(addr).await?;
-    info!(addr = %addr, "Listening");
+    let fast_open = state.config.server.tcp_fast_open || state.config.proxy.upstream_tcp_fast_open;
+    if fast_open && !tcp::fast_open_supported() {
+        warn!("TCP Fast Open is not supported on this platform, ignoring it");
+    }
+    let connection_quota = state
+        .config
+        .server
+        .max_requests_per_connection_per_sec
+        .map(ConnectionRate::quota);
+    let listener = tcp::bind_listener(addr, state.config.server.tcp_fast_open)?;
+    info!(addr = %addr, tcp_fast_open = state.config.server.tcp_fast_open, "Listening");
 
+    serve(listener, state, connection_quota).await;
+    Ok(())
+}
+
+/// Bind and spawn the admin API, if configured. A bind failure is
+/// fatal unless `admin.optional` is set.
+async fn start_admin(state: &Arc<AppState>) -> Result<(), Box<dyn std::error::Error>> {
+    let admin = match &state.config.admin {
+        Some(admin) => admin,
+        None => return Ok(()),
+    };
+    let admin_addr: SocketAddr = admin.listen_addr.parse()?;
+    if admin.token.is_none() && !admin_addr.ip().is_loopback() {
+        return Err("admin.token must be set when admin.listen_addr is not loopback".into());
+    }
+    match TcpListener::bind(admin_addr).await {
+        Ok(admin_listener) => {
+            info!(addr = %admin_addr, "Admin API listening");
+            let admin_timeout = Duration::from_secs(admin.timeout_secs);
+            tokio::spawn(serve_admin(admin_listener, state.clone(), admin_timeout));
+        }
+        Err(e) if admin.optional => {
+            warn!(addr = %admin_addr, error = %e, "Admin API disabled, could not bind");
+        }
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/// Accept proxy connections forever.
+async fn serve(listener: TcpListener, state: Arc<AppState>, connection_quota: Option<Quota>) {
     loop {
         let (stream, remote_addr) = match listener.accept().await {
             Ok(conn) => conn,
@@ -86,78 +162,239 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         };
 
+        let state = state.clone();
+        let connection_rate = connection_quota.map(|quota| Arc::new(ConnectionRate::new(quota)));
+        match state.config.server.min_client_read_bps {
+            Some(min_bps) => {
+                let window = Duration::from_secs(state.config.server.min_client_read_window_secs);
+                let guarded = tcp::SlowReadGuard::new(stream, min_bps, window);
+                serve_client(guarded, state, remote_addr, connection_rate);
+            }
+            None => serve_client(stream, state, remote_addr, connection_rate),
+        }
+    }
+}
+
+/// Serve one client connection on its own task.
+fn serve_client<S>(
+    stream: S,
+    state: Arc<AppState>,
+    remote_addr: SocketAddr,
+    connection_rate: Option<Arc<ConnectionRate>>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let io = TokioIo::new(stream);
+
+    tokio::spawn(async move {
+        let service = service_fn(move |req: Request<Incoming>| {
+            let state = state.clone();
+            let remote_ip = remote_addr.ip();
+            let connection_rate = connection_rate.clone();
+            async move {
+                if let Some(rate) = &connection_rate {
+                    if !rate.check() {
+                        warn!(remote_addr = %remote_ip, "Connection request rate exceeded, closing");
+                        return Ok(ConnectionRate::flood_response());
+                    }
+                }
+                handle_request(req, &state, remote_ip).await
+            }
+        });
+
+        if let Err(err) = http1::Builder::new()
+            .serve_connection(io, service)
+            .with_upgrades()
+            .await
+        {
+            if !err.is_incomplete_message() {
+                warn!(error = %err, "Connection error");
+            }
+        }
+    });
+}
+
+/// Operational endpoints should answer fast or fail fast: slow headers
+/// close the connection and slow handlers get a 503.
+async fn serve_admin(listener: TcpListener, state: Arc<AppState>, timeout: Duration) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(error = %e, "Failed to accept admin connection");
+                continue;
+            }
+        };
+
         let state = state.clone();
         let io = TokioIo::new(stream);
 
         tokio::spawn(async move {
             let service = service_fn(move |req: Request<Incoming>| {
                 let state = state.clone();
-                let remote_ip = remote_addr.ip();
                 async move {
-                    handle_request(req, &state, remote_ip).await
+                    let token = state.config.admin.as_ref().and_then(|a| a.token.as_deref());
+                    let handled = admin::handle(req, &state.rate_limiter, &state.log_filter, token);
+                    match tokio::time::timeout(timeout, handled).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            warn!("Admin request timed out");
+                            Ok(admin::timeout_response())
+                        }
+                    }
                 }
             });
 
             if let Err(err) = http1::Builder::new()
+                .timer(TokioTimer::new())
+                .header_read_timeout(timeout)
                 .serve_connection(io, service)
                 .await
             {
                 if !err.is_incomplete_message() {
-                    warn!(error = %err, "Connection error");
+                    warn!(error = %err, "Admin connection error");
                 }
             }
         });
     }
 }
 
-/// Extract the real client IP from proxy headers.
-/// Stack: client -> WAF -> nginx -> Wardent
-/// The first IP in X-Forwarded-For is the real client.
-fn extract_client_ip(req: &Request<Incoming>, remote_addr: std::net::IpAddr) -> String {
-    // X-Forwarded-For: <client>, <waf>, <nginx>
-    if let Some(xff) = req.headers().get("x-forwarded-for") {
-        if let Ok(xff_str) = xff.to_str() {
-            if let Some(first_ip) = xff_str.split(',').next() {
-                let trimmed = first_ip.trim();
-                if trimmed.parse::<std::net::IpAddr>().is_ok() {
-                    return trimmed.to_string();
-                }
-            }
-        }
+async fn handle_request(
+    req: Request<Incoming>,
+    state: &AppState,
+    remote_addr: std::net::IpAddr,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    let ip = client_ip::resolve(remote_addr, req.headers(), &state.config.server);
+    let client_ip = ip.to_string();
+
+    info!(client_ip = %client_ip, remote_addr = %remote_addr, "Request received");
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+
+    let mut response = process_request(req, state, ip, &client_ip).await?;
+    apply_error_cache_control(&mut response, &state.config.error_redirects.cache_control);
+    state
+        .rate_limiter
+        .record_request(ip, &method, &path, response.status());
+
+    Ok(response)
+}
+
+/// Add `error_redirects.cache_control` to error and redirect responses
+/// Wardent generated itself, e.g. the bot redirect, since a bare 301 is
+/// heuristically cacheable. Upstream responses keep whatever caching the
+/// backend chose, including the default cacheability of a 404 or 410.
+fn apply_error_cache_control(response: &mut Response<Full<Bytes>>, cache_control: &str) {
+    let status = response.status();
+    let applies = status.is_redirection() || status.is_client_error() || status.is_server_error();
+    if cache_control.is_empty() || !applies {
+        return;
+    }
+    if response.extensions().get::<proxy::FromUpstream>().is_some() {
+        return;
     }
 
-    // Fallback: X-Real-IP
-    if let Some(real_ip) = req.headers().get("x-real-ip") {
-        if let Ok(ip_str) = real_ip.to_str() {
-            let trimmed = ip_str.trim();
-            if trimmed.parse::<std::net::IpAddr>().is_ok() {
-                return trimmed.to_string();
-            }
-        }
+    let headers = response.headers_mut();
+    if headers.contains_key(CACHE_CONTROL) {
+        return;
     }
+    if let Ok(value) = HeaderValue::from_str(cache_control) {
+        headers.insert(CACHE_CONTROL, value);
+    }
+}
 
-    // Last resort: raw TCP addr (will be nginx's IP, but better than nothing)
-    remote_addr.to_string()
+/// Which rules a request matches, logged for `logging.explain`.
+#[derive(Debug)]
+struct Explanation<'a> {
+    user_agent_rule: Option<&'a str>,
+    rate_limit_rule: Option<&'a str>,
+    timeout_rule: Option<&'a str>,
+    timeouts: Timeouts,
+    route: String,
 }
 
-async fn handle_request(
-    req: Request<Incoming>,
+fn explain<'a, B>(req: &Request<B>, state: &'a AppState) -> Explanation<'a> {
+    let path = req.uri().path();
+    let user_agent = req.headers().get("user-agent").and_then(|v| v.to_str().ok());
+    let aggregate = state.config.aggregate_for_path(path);
+    let method = req.method().as_str();
+
+    Explanation {
+        user_agent_rule: state.filter.matched_user_agent(user_agent),
+        rate_limit_rule: state.rate_limiter.matched_rule(path),
+        timeout_rule: state.config.timeout_rule_for(method, path).map(|r| r.path.as_str()),
+        timeouts: state.config.timeouts(method, path, aggregate),
+        route: match aggregate {
+            Some(route) => format!("aggregate {}", route.path),
+            None => "upstream".to_string(),
+        },
+    }
+}
+
+async fn process_request(
+    mut req: Request<Incoming>,
     state: &AppState,
-    remote_addr: std::net::IpAddr,
+    ip: std::net::IpAddr,
+    client_ip: &str,
 ) -> Result<Response<Full<Bytes>>, hyper::Error> {
-    let client_ip = extract_client_ip(&req, remote_addr);
-    let ip: std::net::IpAddr = client_ip
-        .parse()
-        .unwrap_or_else(|_| "0.0.0.0".parse().unwrap());
+    // 1. Trailing slash normalization, before any rule sees the path
+    proxy::normalize_trailing_slash(&mut req, &state.config);
 
-    info!(client_ip = %client_ip, remote_addr = %remote_addr, "Request received");
+    // 2. Explain which rules match, when enabled
+    if state.config.logging.explain {
+        let explanation = explain(&req, state);
+        info!(
+            path = req.uri().path(),
+            user_agent_rule = ?explanation.user_agent_rule,
+            rate_limit_rule = ?explanation.rate_limit_rule,
+            timeout_rule = ?explanation.timeout_rule,
+            timeouts = ?explanation.timeouts,
+            route = %explanation.route,
+            "Request explained"
+        );
+    }
+
+    // 3. Load shedding, counted until the request finishes
+    let _active = match &state.load_shedder {
+        Some(shedder) => match shedder.admit(req.uri().path()) {
+            Some(guard) => Some(guard),
+            None => return Ok(LoadShedder::shed_response()),
+        },
+        None => None,
+    };
+
+    // 4. Rate limit check (now per actual client, not nginx); a solved
+    // challenge is credited first so it can lower the violation count
+    state.rate_limiter.check_challenge(ip, req.headers());
+    let path = req.uri().path();
+    let limited = state.rate_limiter.check_rate_limit(ip, path, &state.config.error_redirects);
+    if let Some(response) = limited {
+        return Ok(response);
+    }
+    if let Some(response) = state.rate_limiter.check_global(ip) {
+        return Ok(response);
+    }
 
-    // 1. Rate limit check (now per actual client, not nginx)
-    if let Some(response) = state.rate_limiter.check_rate_limit(ip, &state.config.error_redirects) {
+    // 5. Header injection filter
+    if let Some(response) = state.filter.check_headers(req.headers()) {
         return Ok(response);
     }
 
-    // 2. User-agent filter
+    // 6. CONNECT tunnels in forward proxy mode skip the reverse-proxy steps
+    if let Some(tunnels) = &state.config.forward_proxy {
+        if forward_proxy::is_connect(&req) {
+            let connect_timeout = state.config.limits.connect_timeout_secs.map(Duration::from_secs);
+            return Ok(forward_proxy::handle(req, tunnels, connect_timeout).await);
+        }
+    }
+
+    // 7. Host header allowlist
+    if let Some(response) = state.host_check.check(&mut req) {
+        return Ok(response);
+    }
+
+    // 8. User-agent filter
     let user_agent = req
         .headers()
         .get("user-agent")
@@ -167,7 +404,299 @@ async fn handle_request(
         return Ok(response);
     }
 
-    // 3. Forward to upstream
+    // 9. Query string limits and schema
+    if let Some(response) = query::check_limits(req.uri().query(), &state.config.limits) {
+        return Ok(response);
+    }
+    if let Some(response) = state.query_schema.check(&mut req) {
+        return Ok(response);
+    }
+
+    // 10. Nonce/timestamp replay protection
+    if let Some(replay_guard) = &state.replay_guard {
+        if let Some(response) = replay_guard.check(req.uri().path(), req.headers()) {
+            return Ok(response);
+        }
+    }
+
+    // 11. JWT validation; claims go upstream as headers
+    if let Some(jwt) = &state.jwt {
+        let path = req.uri().path().to_string();
+        if let Some(response) = jwt.check(&path, req.headers_mut()).await {
+            return Ok(response);
+        }
+    }
+
+    // 12. Per-user concurrency cap, released when the request finishes
+    let user = state
+        .config
+        .rate_limit
+        .user_header
+        .as_deref()
+        .and_then(|name| req.headers().get(name))
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let _in_flight = match state.rate_limiter.acquire_concurrency(user.as_deref(), ip) {
+        Some(guard) => guard,
+        None => return Ok(RateLimit::concurrency_limited_response()),
+    };
+
+    // 13. Per-session serialization for clients that need ordering
+    let session = state.session_ordering.as_ref().and_then(|ordering| {
+        req.headers()
+            .get(ordering.header())
+            .and_then(|v| v.to_str().ok())
+            .map(|id| (ordering, id.to_string()))
+    });
+    let _session_turn = match session {
+        Some((ordering, id)) => match ordering.acquire(&id).await {
+            Some(guard) => Some(guard),
+            None => return Ok(SessionOrdering::queue_full_response()),
+        },
+        None => None,
+    };
+
+    // 14. Composite endpoints answered by fanning out upstream
+    if let Some(route) = state.config.aggregate_for_path(req.uri().path()) {
+        return aggregate::handle(req, route, &state.config, client_ip).await;
+    }
+
+    // 15. Forward to upstream
     // test comment cuz something is wrong
-    proxy::forward(req, &state.config, &client_ip).await
-}
\ No newline at end of file
+    proxy::forward(req, &state.config, client_ip).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+
+    /// A minimal valid config with `overrides` (TOML) merged over it. The
+    /// library's own test config is compiled out of the build the binary
+    /// links against, so this mirrors it.
+    fn test_config(overrides: &str) -> Config {
+        const BASE: &str = r#"
+            [server]
+            listen_addr = "127.0.0.1:0"
+
+            [proxy]
+            upstream = "http://127.0.0.1:9"
+            secret_key = "test"
+
+            [limits]
+            max_body_size = 1024
+            default_timeout_secs = 30
+
+            [rate_limit]
+            requests_per_minute = 60
+            burst_size = 10
+
+            [filter]
+            blocked_user_agents = []
+            redirect_url = "https://example.com/"
+
+            [error_redirects]
+            rate_limited = "/429"
+            banned = "/403"
+            body_too_large = "/413"
+            timeout = "/408"
+            bad_gateway = "/502"
+        "#;
+
+        fn merge(base: &mut toml::Table, overlay: toml::Table) {
+            for (key, value) in overlay {
+                match (base.get_mut(&key), value) {
+                    (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => merge(base, overlay),
+                    (_, value) => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+
+        let mut table: toml::Table = toml::from_str(BASE).unwrap();
+        merge(&mut table, toml::from_str(overrides).unwrap());
+        toml::Value::Table(table).try_into().unwrap()
+    }
+
+    fn test_state(overrides: &str) -> Arc<AppState> {
+        let (_, log_filter) = reload::Layer::new(EnvFilter::new("wardent=info"));
+        Arc::new(AppState::new(test_config(overrides), log_filter))
+    }
+
+    fn response(status: StatusCode) -> Response<Full<Bytes>> {
+        Response::builder().status(status).body(Full::new(Bytes::new())).unwrap()
+    }
+
+    #[test]
+    fn generated_errors_get_cache_control() {
+        let mut response = response(StatusCode::BAD_GATEWAY);
+        apply_error_cache_control(&mut response, "no-store");
+        assert_eq!(response.headers()[CACHE_CONTROL], "no-store");
+    }
+
+    #[test]
+    fn upstream_errors_are_left_alone() {
+        let mut response = response(StatusCode::NOT_FOUND);
+        response.extensions_mut().insert(proxy::FromUpstream);
+        apply_error_cache_control(&mut response, "no-store");
+        assert!(!response.headers().contains_key(CACHE_CONTROL));
+    }
+
+    #[test]
+    fn generated_redirects_get_cache_control() {
+        let filter = Filter::new(&test_config("filter.blocked_user_agents = [\"badbot\"]").filter);
+        let mut redirect = filter.check_user_agent(Some("badbot/1.0")).unwrap();
+        assert_eq!(redirect.status(), StatusCode::MOVED_PERMANENTLY);
+        apply_error_cache_control(&mut redirect, "no-store");
+        assert_eq!(redirect.headers()[CACHE_CONTROL], "no-store");
+
+        let mut upstream_redirect = response(StatusCode::MOVED_PERMANENTLY);
+        upstream_redirect.extensions_mut().insert(proxy::FromUpstream);
+        apply_error_cache_control(&mut upstream_redirect, "no-store");
+        assert!(!upstream_redirect.headers().contains_key(CACHE_CONTROL));
+    }
+
+    #[test]
+    fn existing_cache_control_and_successes_are_kept() {
+        let mut error = response(StatusCode::TOO_MANY_REQUESTS);
+        error.headers_mut().insert(CACHE_CONTROL, HeaderValue::from_static("max-age=5"));
+        apply_error_cache_control(&mut error, "no-store");
+        assert_eq!(error.headers()[CACHE_CONTROL], "max-age=5");
+
+        let mut ok = response(StatusCode::OK);
+        apply_error_cache_control(&mut ok, "no-store");
+        assert!(!ok.headers().contains_key(CACHE_CONTROL));
+    }
+
+    #[tokio::test]
+    async fn flooding_connection_is_closed() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let state = test_state("server.max_requests_per_connection_per_sec = 2");
+        let quota = ConnectionRate::quota(2);
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let remote_addr: SocketAddr = "203.0.113.1:40000".parse().unwrap();
+        serve_client(server, state, remote_addr, Some(Arc::new(ConnectionRate::new(quota))));
+
+        let (mut reader, mut writer) = tokio::io::split(client);
+        let request = "GET /flood HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        writer.write_all(request.repeat(4).as_bytes()).await.unwrap();
+
+        let mut responses = String::new();
+        tokio::time::timeout(Duration::from_secs(5), reader.read_to_string(&mut responses))
+            .await
+            .expect("connection was not closed")
+            .unwrap();
+        let statuses: Vec<&str> = responses
+            .lines()
+            .filter(|line| line.starts_with("HTTP/1.1"))
+            .collect();
+        assert_eq!(statuses.len(), 3, "{responses}");
+        assert!(statuses[2].starts_with("HTTP/1.1 429"), "{responses}");
+        assert!(responses.to_ascii_lowercase().contains("connection: close"), "{responses}");
+    }
+
+    /// Write `request` to a fresh in-memory connection and return all
+    /// the server sends back before closing it.
+    async fn exchange(state: Arc<AppState>, request: &[u8]) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let remote_addr: SocketAddr = "203.0.113.1:40000".parse().unwrap();
+        serve_client(server, state, remote_addr, None);
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+            .await
+            .expect("connection was not closed")
+            .unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[tokio::test]
+    async fn control_character_in_header_is_a_bad_request() {
+        let state = test_state("filter.reject_control_chars = true");
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\nX-Note: a\x01b\r\n\r\n";
+        let response = exchange(state, request).await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn slow_admin_client_is_timed_out() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let state = test_state("[admin]\nlisten_addr = \"127.0.0.1:0\"\ntimeout_secs = 1");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_admin(listener, state, Duration::from_secs(1)));
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET /history/203.0.113.1 HTTP/1.1\r\n").await.unwrap();
+
+        let started = tokio::time::Instant::now();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+            .await
+            .expect("slow admin client was not timed out")
+            .unwrap();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(900) && elapsed < Duration::from_secs(3), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn occupied_admin_port_is_skipped_when_optional() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let occupied = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin = format!("[admin]\nlisten_addr = \"{}\"", occupied.local_addr().unwrap());
+        assert!(start_admin(&test_state(&admin)).await.is_err());
+
+        let state = test_state(&format!("{admin}\noptional = true"));
+        start_admin(&state).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, state, None));
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_string(&mut response))
+            .await
+            .expect("proxy did not answer")
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 502"), "{response}");
+    }
+
+    #[test]
+    fn explanation_names_the_matched_route_and_timeout_rule() {
+        let state = test_state(
+            r#"
+            [[timeout_override]]
+            path = "/dash"
+            methods = ["GET"]
+            timeout_secs = 7
+
+            [[aggregate]]
+            path = "/dashboard"
+            request = []
+            "#,
+        );
+
+        let req = Request::get("/dashboard").body(()).unwrap();
+        let explanation = explain(&req, &state);
+        assert_eq!(explanation.route, "aggregate /dashboard");
+        assert_eq!(explanation.timeout_rule, Some("/dash"));
+        assert_eq!(explanation.timeouts.total_secs, 7);
+
+        let req = Request::post("/api").body(()).unwrap();
+        let explanation = explain(&req, &state);
+        assert_eq!(explanation.route, "upstream");
+        assert_eq!(explanation.timeout_rule, None);
+    }
+}
@@ -1,23 +1,40 @@
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use http_body_util::{BodyExt, Full};
-use hyper::body::Incoming;
-use hyper::{Request, Response, StatusCode, Uri};
+use hyper::body::Body;
+use hyper::{HeaderMap, Method, Request, Response, StatusCode, Uri};
 use std::time::Duration;
-use tracing::{error, info, instrument};
+use tracing::{error, info, instrument, warn};
 
-use crate::config::Config;
+use crate::config::{
+    Config, HeaderCase, RedirectLoopAction, Timeouts, TrailingSlash, TruncatedResponseAction,
+    UpstreamAcceptEncoding,
+};
+use crate::{decompress, rewrite, signing, tcp};
 
 #[instrument(skip_all, fields(method = %req.method(), path = %req.uri().path()))]
-pub async fn forward(
-    req: Request<Incoming>,
+pub async fn forward<B>(
+    req: Request<B>,
     config: &Config,
     client_ip: &str,
-) -> Result<Response<Full<Bytes>>, hyper::Error> {
+) -> Result<Response<Full<Bytes>>, hyper::Error>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    let started = tokio::time::Instant::now();
+    let mut req = req;
     let path = req.uri().path().to_string();
-    let method = req.method().clone();
+    // Resolved before the timeouts, so an overridden DELETE gets the
+    // DELETE timeout rules rather than the POST ones.
+    let method = if config.proxy.honor_method_override {
+        apply_method_override(req.method().clone(), req.headers_mut())
+    } else {
+        req.method().clone()
+    };
 
-    let timeout_secs = config.timeout_for_path(&path);
+    let timeouts = config.timeouts(method.as_str(), &path, None);
+    let timeout_secs = timeouts.total_secs;
     let timeout = Duration::from_secs(timeout_secs);
+    let max_response = timeouts.max_response_ms.map(Duration::from_millis);
 
     info!(
         client_ip = client_ip,
@@ -25,77 +42,276 @@ pub async fn forward(
         "Forwarding request"
     );
 
-    let body_result = tokio::time::timeout(timeout, collect_body(req, config)).await;
+    let (mut parts, mut body_bytes) = match read_body(req, config, timeout).await {
+        Ok(result) => result,
+        Err(response) => return Ok(response),
+    };
 
-    let (parts, body_bytes) = match body_result {
-        Ok(Ok(result)) => result,
-        Ok(Err(response)) => return Ok(response),
-        Err(_) => {
-            error!("Timeout reading request body");
-            return Ok(status_response(StatusCode::GATEWAY_TIMEOUT));
+    if let Some(response) =
+        decompress::check_request_body(&mut parts.headers, &mut body_bytes, &config.limits)
+    {
+        return Ok(response);
+    }
+
+    let path_and_query = parts.uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let outgoing = match build_upstream_request(
+        method,
+        path_and_query,
+        &parts.headers,
+        client_ip,
+        config,
+        body_bytes,
+    ) {
+        Some(outgoing) => outgoing,
+        None => return Ok(status_response(StatusCode::BAD_GATEWAY)),
+    };
+
+    let upstream = send_upstream(outgoing, config, &timeouts);
+    let result = match max_response {
+        Some(max_response) => match tokio::time::timeout_at(started + max_response, upstream).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!(
+                    path = path,
+                    max_response_ms = max_response.as_millis() as u64,
+                    "Response time target exceeded, failing fast"
+                );
+                return Ok(status_response(StatusCode::SERVICE_UNAVAILABLE));
+            }
+        },
+        None => upstream.await,
+    };
+
+    match result {
+        Ok(response) => {
+            let loops = config.proxy.redirect_loop != RedirectLoopAction::Ignore
+                && redirects_to_itself(&response, &parts, config);
+            if !loops {
+                return Ok(response);
+            }
+            warn!(
+                path = path,
+                status = response.status().as_u16(),
+                "Upstream redirected to the requested URL"
+            );
+            match config.proxy.redirect_loop {
+                RedirectLoopAction::Fail => Ok(status_response(StatusCode::BAD_GATEWAY)),
+                _ => Ok(response),
+            }
+        }
+        Err(UpstreamError::Failed(e)) => {
+            error!(error = %e, "Upstream request failed");
+            Ok(status_response(StatusCode::BAD_GATEWAY))
+        }
+        Err(UpstreamError::Timeout) => {
+            error!(path = path, timeout_secs = timeout_secs, "Upstream timeout");
+            Ok(status_response(StatusCode::GATEWAY_TIMEOUT))
         }
+    }
+}
+
+/// Apply `proxy.trailing_slash` to the request path, keeping the query.
+pub fn normalize_trailing_slash<B>(req: &mut Request<B>, config: &Config) {
+    let normalized = match trailing_slash_path(req.uri().path(), config) {
+        Some(normalized) => normalized,
+        None => return,
+    };
+
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", normalized, query),
+        None => normalized,
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+    }
+}
+
+/// `path` with `proxy.trailing_slash` applied, or None if it stays as is.
+fn trailing_slash_path(path: &str, config: &Config) -> Option<String> {
+    match config.proxy.trailing_slash {
+        TrailingSlash::Preserve => None,
+        _ if path == "/" => None,
+        _ if config.proxy.trailing_slash_exempt.iter().any(|p| path.starts_with(p.as_str())) => None,
+        _ if path.rsplit('/').find(|s| !s.is_empty()).is_some_and(|s| s.contains('.')) => None,
+        TrailingSlash::Add if !path.ends_with('/') => Some(format!("{}/", path)),
+        TrailingSlash::Remove if path.ends_with('/') => Some(path.trim_end_matches('/').to_string()),
+        _ => None,
+    }
+}
+
+const METHOD_OVERRIDE_HEADER: &str = "x-http-method-override";
+
+/// Legacy clients tunnel PUT/PATCH/DELETE through POST with the real
+/// method in X-HTTP-Method-Override. Only POST may be overridden, and
+/// only to those methods; the header is removed either way.
+fn apply_method_override(method: Method, headers: &mut HeaderMap) -> Method {
+    let requested = match headers.remove(METHOD_OVERRIDE_HEADER) {
+        Some(value) => value,
+        None => return method,
     };
 
+    if method != Method::POST {
+        return method;
+    }
+
+    let requested = requested.to_str().unwrap_or("").trim().to_ascii_uppercase();
+    match requested.as_str() {
+        "PUT" => Method::PUT,
+        "PATCH" => Method::PATCH,
+        "DELETE" => Method::DELETE,
+        _ => {
+            warn!(requested = %requested, "Ignoring disallowed method override");
+            method
+        }
+    }
+}
+
+/// Build the request sent to the upstream: target URI, client headers
+/// minus hop-by-hop ones, and the forwarding headers Wardent adds.
+/// Returns None if the upstream URI can't be built.
+pub(crate) fn build_upstream_request(
+    method: Method,
+    path_and_query: &str,
+    headers: &HeaderMap,
+    client_ip: &str,
+    config: &Config,
+    body: Bytes,
+) -> Option<Request<Full<Bytes>>> {
     let upstream_uri = format!(
         "{}{}",
         config.proxy.upstream.trim_end_matches('/'),
-        parts.uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/")
+        path_and_query
     );
 
     let upstream_uri: Uri = match upstream_uri.parse() {
         Ok(uri) => uri,
         Err(e) => {
             error!(error = %e, "Failed to parse upstream URI");
-            return Ok(status_response(StatusCode::BAD_GATEWAY));
+            return None;
         }
     };
 
+    // Content-Length always reflects the body actually sent, never the
+    // client's header. Empty bodies only get one if the client sent one
+    // or normalize_empty_body asks for it.
+    let send_length = !body.is_empty()
+        || headers.contains_key(hyper::header::CONTENT_LENGTH)
+        || (config.proxy.normalize_empty_body
+            && matches!(method, Method::POST | Method::PUT | Method::PATCH));
+
     let mut builder = Request::builder()
         .method(method)
         .uri(upstream_uri);
 
-    for (name, value) in parts.headers.iter() {
+    for (name, value) in headers.iter() {
         let name_str = name.as_str().to_lowercase();
         if matches!(
             name_str.as_str(),
             "connection" | "keep-alive" | "transfer-encoding" | "te" | "trailer" | "upgrade"
+                | "content-length" | signing::SIGNATURE_HEADER | signing::TIMESTAMP_HEADER
         ) {
             continue;
         }
+        if name == hyper::header::ACCEPT_ENCODING
+            && config.proxy.upstream_accept_encoding == UpstreamAcceptEncoding::Strip
+        {
+            continue;
+        }
         builder = builder.header(name, value);
     }
+    if config.proxy.upstream_accept_encoding == UpstreamAcceptEncoding::Strip {
+        builder = builder.header(hyper::header::ACCEPT_ENCODING, "identity");
+    }
+    if send_length {
+        builder = builder.header(hyper::header::CONTENT_LENGTH, body.len());
+    }
     builder = builder.header("X-Forwarded-For", client_ip);
-    builder = builder.header("X-Forwarded-Proto", "https");
+    let path = path_and_query.split('?').next().unwrap_or(path_and_query);
+    builder = builder.header("X-Forwarded-Proto", config.forwarded_proto_for_path(path));
     builder = builder.header("X-Wardent-Secret", &config.proxy.secret_key);
 
-    let outgoing = builder
-        .body(Full::new(body_bytes.clone()))
+    let mut request = builder
+        .body(Full::new(body.clone()))
         .expect("Failed to build outgoing request");
 
-    let upstream_result = tokio::time::timeout(
-        timeout,
-        send_upstream(outgoing, &config.proxy.upstream),
-    )
-        .await;
+    if config.proxy.merge_duplicate_headers {
+        merge_duplicate_headers(request.headers_mut());
+    }
 
-    match upstream_result {
-        Ok(Ok(response)) => Ok(response),
-        Ok(Err(e)) => {
-            error!(error = %e, "Upstream request failed");
-            Ok(status_response(StatusCode::BAD_GATEWAY))
-        }
+    if let Some(signing) = &config.proxy.signing {
+        let (method, uri) = (request.method().clone(), request.uri().clone());
+        signing::sign(signing, &method, &uri, &body, request.headers_mut());
+    }
+
+    Some(request)
+}
+
+/// Response extension marking a response relayed from the upstream, as
+/// opposed to one Wardent generated.
+#[derive(Debug, Clone, Copy)]
+pub struct FromUpstream;
+
+pub(crate) enum UpstreamError {
+    Timeout,
+    Failed(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl<E: Into<Box<dyn std::error::Error + Send + Sync>>> From<E> for UpstreamError {
+    fn from(e: E) -> Self {
+        UpstreamError::Failed(e.into())
+    }
+}
+
+/// Read the request body within the path timeout. With
+/// `limits.body_idle_timeout_secs` set, a slow-but-alive upload may run
+/// past the path timeout once, up to twice its length; a connection
+/// that stops sending bytes for the idle period is cut off sooner.
+async fn read_body<B>(
+    req: Request<B>,
+    config: &Config,
+    timeout: Duration,
+) -> Result<(hyper::http::request::Parts, Bytes), Response<Full<Bytes>>>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    let idle_timeout = config.limits.body_idle_timeout_secs.map(Duration::from_secs);
+    let limit = match idle_timeout {
+        Some(_) => timeout * 2,
+        None => timeout,
+    };
+
+    match tokio::time::timeout(limit, collect_body(req, config, idle_timeout)).await {
+        Ok(result) => result,
         Err(_) => {
-            error!(path = path, timeout_secs = timeout_secs, "Upstream timeout");
-            Ok(status_response(StatusCode::GATEWAY_TIMEOUT))
+            error!(limit_secs = limit.as_secs(), "Timeout reading request body");
+            Err(status_response(StatusCode::GATEWAY_TIMEOUT))
         }
     }
 }
 
-async fn collect_body(
-    req: Request<Incoming>,
+async fn collect_body<B>(
+    req: Request<B>,
     config: &Config,
-) -> Result<(hyper::http::request::Parts, Bytes), Response<Full<Bytes>>> {
-    let max_size = config.limits.max_body_size;
+    idle_timeout: Option<Duration>,
+) -> Result<(hyper::http::request::Parts, Bytes), Response<Full<Bytes>>>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    let mut max_size = config.limits.max_body_size;
+    if let Some(max_request) = config.limits.max_request_size {
+        let head = head_size(&req);
+        if head > max_request {
+            warn!(
+                head_bytes = head,
+                max_request_size = max_request,
+                "Request headers exceed total size limit"
+            );
+            return Err(status_response(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+        max_size = max_size.min(max_request - head);
+    }
 
     if let Some(content_length) = req.headers().get("content-length") {
         if let Ok(len_str) = content_length.to_str() {
@@ -107,35 +323,269 @@ async fn collect_body(
         }
     }
 
-    let (parts, body) = req.into_parts();
+    let (parts, mut body) = req.into_parts();
+    let mut buf = BytesMut::new();
+
+    loop {
+        let frame = match idle_timeout {
+            Some(idle) => match tokio::time::timeout(idle, body.frame()).await {
+                Ok(frame) => frame,
+                Err(_) => {
+                    warn!(
+                        received_bytes = buf.len(),
+                        idle_secs = idle.as_secs(),
+                        "Request body stalled"
+                    );
+                    return Err(status_response(StatusCode::REQUEST_TIMEOUT));
+                }
+            },
+            None => body.frame().await,
+        };
 
-    let collected = body.collect().await;
-    match collected {
-        Ok(collected) => {
-            let body_bytes = collected.to_bytes();
-            if body_bytes.len() as u64 > max_size {
-                return Err(status_response(StatusCode::PAYLOAD_TOO_LARGE));
+        match frame {
+            Some(Ok(frame)) => {
+                if let Ok(data) = frame.into_data() {
+                    buf.extend_from_slice(&data);
+                    if buf.len() as u64 > max_size {
+                        return Err(status_response(StatusCode::PAYLOAD_TOO_LARGE));
+                    }
+                }
             }
-            Ok((parts, body_bytes))
+            Some(Err(_)) => return Err(status_response(StatusCode::BAD_GATEWAY)),
+            None => break,
         }
-        Err(_) => Err(status_response(StatusCode::BAD_GATEWAY)),
     }
+
+    Ok((parts, buf.freeze()))
 }
 
-async fn send_upstream(
+/// Join each repeated header into a single one, as HTTP allows for
+/// list-valued fields: `, ` in general, `; ` for Cookie.
+fn merge_duplicate_headers(headers: &mut HeaderMap) {
+    let repeated: Vec<hyper::header::HeaderName> = headers
+        .keys()
+        .filter(|name| headers.get_all(*name).iter().nth(1).is_some())
+        .cloned()
+        .collect();
+
+    for name in repeated {
+        let separator: &[u8] = if name == hyper::header::COOKIE { b"; " } else { b", " };
+        let values: Vec<&[u8]> = headers.get_all(&name).iter().map(|v| v.as_bytes()).collect();
+        let merged = values.join(separator);
+        if let Ok(value) = hyper::header::HeaderValue::from_bytes(&merged) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// Whether a 3xx response points back at the URL that was requested,
+/// either as a path or as an absolute URL on the client's Host or the
+/// upstream. Following it would only get the same redirect again. The
+/// Location path is compared after `proxy.trailing_slash`, which is what
+/// the client's next request goes through, and an absolute Location only
+/// counts when its scheme matches too, so an http to https redirect is
+/// not a loop.
+fn redirects_to_itself(
+    response: &Response<Full<Bytes>>,
+    parts: &hyper::http::request::Parts,
+    config: &Config,
+) -> bool {
+    if !response.status().is_redirection() {
+        return false;
+    }
+    let location = match response
+        .headers()
+        .get(hyper::header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<Uri>().ok())
+    {
+        Some(location) => location,
+        None => return false,
+    };
+
+    if let Some(authority) = location.authority() {
+        let scheme = location.scheme_str().unwrap_or_default();
+        let host = parts.headers.get(hyper::header::HOST).and_then(|v| v.to_str().ok());
+        let client_scheme = config.forwarded_proto_for_path(parts.uri.path());
+        let upstream = config.proxy.upstream.parse::<Uri>().ok();
+        let same_origin = (host.is_some_and(|h| h.eq_ignore_ascii_case(authority.as_str()))
+            && scheme.eq_ignore_ascii_case(client_scheme))
+            || upstream.as_ref().is_some_and(|u| {
+                u.authority() == Some(authority) && u.scheme_str() == Some(scheme)
+            });
+        if !same_origin {
+            return false;
+        }
+    }
+
+    let location_path = location.path();
+    if !location_path.starts_with('/') {
+        return false;
+    }
+    let target_path = trailing_slash_path(location_path, config);
+    let target_path = target_path.as_deref().unwrap_or(location_path);
+    target_path == parts.uri.path() && location.query() == parts.uri.query()
+}
+
+/// A response with both Transfer-Encoding and Content-Length, or with
+/// Content-Length values that disagree, could be read differently by
+/// the client or a cache in between. Such responses are never relayed.
+fn ambiguous_framing(headers: &HeaderMap) -> bool {
+    let mut lengths = headers
+        .get_all(hyper::header::CONTENT_LENGTH)
+        .iter()
+        .flat_map(|value| value.as_bytes().split(|b| *b == b','))
+        .map(|value| value.trim_ascii());
+    let first = match lengths.next() {
+        Some(first) => first,
+        None => return false,
+    };
+
+    headers.contains_key(hyper::header::TRANSFER_ENCODING) || lengths.any(|other| other != first)
+}
+
+/// Approximate wire size of the request line and headers, as counted
+/// against limits.max_request_size.
+fn head_size<B>(req: &Request<B>) -> u64 {
+    let request_line = req.method().as_str().len() + req.uri().to_string().len() + 12;
+    let headers: usize = req
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum();
+    (request_line + headers) as u64
+}
+
+/// Send the request upstream and buffer the response.
+///
+/// `timeouts.total_secs` is a total deadline for the round trip, and
+/// `proxy.ttfb_timeout_secs` can cut the wait for headers shorter. With
+/// `proxy.upstream_idle_timeout_secs` set the total deadline only covers
+/// the response headers; the body is then read with an idle timeout
+/// reset on every chunk, so a slow but steady download isn't cut off
+/// before twice the deadline.
+pub(crate) async fn send_upstream(
     req: Request<Full<Bytes>>,
-    _upstream_base: &str,
-) -> Result<Response<Full<Bytes>>, Box<dyn std::error::Error + Send + Sync>> {
+    config: &Config,
+    timeouts: &Timeouts,
+) -> Result<Response<Full<Bytes>>, UpstreamError> {
+    use hyper_util::client::legacy::connect::HttpConnector;
     use hyper_util::client::legacy::Client;
     use hyper_util::rt::TokioExecutor;
 
-    let client: Client<_, Full<Bytes>> =
-        Client::builder(TokioExecutor::new()).build_http();
+    let is_head = req.method() == Method::HEAD;
+    let connect_timeout = timeouts.connect_secs.map(Duration::from_secs);
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(timeouts.total_secs);
+    let idle_timeout = config.proxy.upstream_idle_timeout_secs.map(Duration::from_secs);
+
+    let mut builder = Client::builder(TokioExecutor::new());
+    builder.http1_title_case_headers(config.proxy.normalize_header_case == HeaderCase::TitleCase);
+
+    let pending = if config.proxy.upstream_tcp_fast_open {
+        builder
+            .build(tcp::FastOpenConnector::new(connect_timeout))
+            .request(req)
+    } else {
+        let mut connector = HttpConnector::new();
+        connector.set_connect_timeout(connect_timeout);
+        builder.build(connector).request(req)
+    };
+
+    let headers_deadline = match config.proxy.ttfb_timeout_secs {
+        Some(ttfb) => deadline.min(tokio::time::Instant::now() + Duration::from_secs(ttfb)),
+        None => deadline,
+    };
+    let response = match tokio::time::timeout_at(headers_deadline, pending).await {
+        Ok(response) => response?,
+        Err(_) => {
+            warn!("No response headers from upstream in time");
+            return Err(UpstreamError::Timeout);
+        }
+    };
+    let (mut parts, mut body) = response.into_parts();
+
+    if ambiguous_framing(&parts.headers) {
+        error!("Upstream response has ambiguous framing");
+        return Err("ambiguous upstream response framing".into());
+    }
 
-    let response = client.request(req).await?;
-    let (parts, body) = response.into_parts();
-    let body_bytes = body.collect().await?.to_bytes();
+    let declared_len = parts
+        .headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
 
+    // The idle timeout lets a steady download run past the deadline, but
+    // only up to twice the path timeout.
+    let body_deadline = deadline + Duration::from_secs(timeouts.total_secs);
+
+    let mut buf = BytesMut::new();
+    loop {
+        let frame = match idle_timeout {
+            Some(idle) => {
+                let idle_deadline = tokio::time::Instant::now() + idle;
+                tokio::time::timeout_at(idle_deadline.min(body_deadline), body.frame()).await
+            }
+            None => tokio::time::timeout_at(deadline, body.frame()).await,
+        };
+
+        match frame.map_err(|_| UpstreamError::Timeout)? {
+            Some(Ok(frame)) => {
+                if let Ok(data) = frame.into_data() {
+                    buf.extend_from_slice(&data);
+                    if let Some(max) = config.limits.max_response_size {
+                        if buf.len() as u64 > max {
+                            error!(max_response_size = max, "Upstream response too large");
+                            return Err("upstream response too large".into());
+                        }
+                    }
+                }
+            }
+            Some(Err(e)) => match declared_len {
+                Some(declared) if buf.len() < declared => {
+                    if config.proxy.truncated_response == TruncatedResponseAction::Fail {
+                        error!(
+                            received = buf.len(),
+                            declared = declared,
+                            "Truncated upstream response"
+                        );
+                        return Err(e.into());
+                    }
+                    warn!(
+                        received = buf.len(),
+                        declared = declared,
+                        "Truncated upstream response, forwarding partial body"
+                    );
+                    parts.headers.insert(hyper::header::CONTENT_LENGTH, buf.len().into());
+                    break;
+                }
+                _ => return Err(e.into()),
+            },
+            None => break,
+        }
+    }
+    let mut body_bytes = buf.freeze();
+
+    // The body is buffered, so it goes to the client with Content-Length
+    // framing; a chunked upstream encoding must not be passed on.
+    if parts.headers.remove(hyper::header::TRANSFER_ENCODING).is_some() && !is_head {
+        parts.headers.insert(hyper::header::CONTENT_LENGTH, body_bytes.len().into());
+    }
+
+    if !config.response_rewrite.is_empty() {
+        body_bytes = rewrite::apply(&mut parts, body_bytes, &config.response_rewrite);
+    }
+
+    if config.proxy.enforce_content_type
+        && !body_bytes.is_empty()
+        && !parts.headers.contains_key(hyper::header::CONTENT_TYPE)
+    {
+        if let Ok(value) = config.proxy.default_content_type.parse() {
+            parts.headers.insert(hyper::header::CONTENT_TYPE, value);
+        }
+    }
+
+    parts.extensions.insert(FromUpstream);
     Ok(Response::from_parts(parts, Full::new(body_bytes)))
 }
 
@@ -145,4 +595,697 @@ fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
         .header("Content-Length", "0")
         .body(Full::new(Bytes::new()))
         .unwrap()
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use futures_util::stream::{self, Stream, StreamExt};
+    use http_body_util::StreamBody;
+    use hyper::body::Frame;
+    use std::convert::Infallible;
+    use std::pin::Pin;
+
+    type TestBody =
+        StreamBody<Pin<Box<dyn Stream<Item = Result<Frame<Bytes>, Infallible>> + Send>>>;
+
+    /// A POST whose body sends `chunks` one-byte chunks, one every
+    /// `every`, then either ends or stalls forever.
+    fn trickle(chunks: usize, every: Duration, stall: bool) -> Request<TestBody> {
+        let sent = stream::unfold(0, move |n| async move {
+            if n == chunks {
+                return None;
+            }
+            tokio::time::sleep(every).await;
+            Some((Ok(Frame::data(Bytes::from_static(b"x"))), n + 1))
+        });
+        let body: Pin<Box<dyn Stream<Item = _> + Send>> = if stall {
+            Box::pin(sent.chain(stream::pending()))
+        } else {
+            Box::pin(sent)
+        };
+        Request::post("/upload").body(StreamBody::new(body)).unwrap()
+    }
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    /// Accept one connection on a local port, read the request head, then
+    /// write `head` followed by `chunk` every `gap` until the client hangs
+    /// up. Returns the upstream URL.
+    async fn trickling_upstream(head: &'static str, chunk: &'static [u8], gap: Duration) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(head.as_bytes()).await.unwrap();
+            while stream.write_all(chunk).await.is_ok() {
+                tokio::time::sleep(gap).await;
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    /// Accept one connection on a local port, read the request head and
+    /// any Content-Length body, answer with `response` and return the
+    /// request as it arrived on the wire.
+    async fn stub_upstream(response: &'static str) -> (String, tokio::sync::oneshot::Receiver<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            let head_len = loop {
+                if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end + 4;
+                }
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            };
+            let head = String::from_utf8_lossy(&request[..head_len]).to_ascii_lowercase();
+            let body_len = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .map_or(0, |len| len.trim().parse::<usize>().unwrap());
+            while request.len() < head_len + body_len {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+            let _ = tx.send(String::from_utf8_lossy(&request).into_owned());
+        });
+        (format!("http://{addr}"), rx)
+    }
+
+    /// A stub upstream answering 204.
+    async fn recording_upstream() -> (String, tokio::sync::oneshot::Receiver<String>) {
+        stub_upstream("HTTP/1.1 204 No Content\r\n\r\n").await
+    }
+
+    fn upstream_get(upstream: &str) -> Request<Full<Bytes>> {
+        Request::get(format!("{upstream}/")).body(Full::new(Bytes::new())).unwrap()
+    }
+
+    fn timeouts(total_secs: u64) -> Timeouts {
+        Timeouts { total_secs, connect_secs: None, max_response_ms: None }
+    }
+
+    #[tokio::test]
+    async fn endless_download_is_capped_at_twice_the_deadline() {
+        let upstream = trickling_upstream(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"1\r\nx\r\n",
+            Duration::from_millis(100),
+        )
+        .await;
+        let config = test_config("proxy.upstream_idle_timeout_secs = 1");
+
+        let started = tokio::time::Instant::now();
+        let result = send_upstream(upstream_get(&upstream), &config, &timeouts(1)).await;
+        assert!(matches!(result, Err(UpstreamError::Timeout)));
+        assert!(started.elapsed() < 3 * SECOND);
+    }
+
+    #[tokio::test]
+    async fn steady_download_outlives_the_total_timeout() {
+        let upstream = trickling_upstream(
+            "HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\n",
+            b"x",
+            Duration::from_millis(200),
+        )
+        .await;
+        let config = test_config("proxy.upstream_idle_timeout_secs = 1");
+
+        let started = tokio::time::Instant::now();
+        let response = send_upstream(upstream_get(&upstream), &config, &timeouts(1)).await;
+        let body = response.ok().unwrap().into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"xxxxxxxx");
+        assert!(started.elapsed() > SECOND);
+    }
+
+    #[tokio::test]
+    async fn stalled_download_fails_on_the_idle_timeout() {
+        let upstream = trickling_upstream(
+            "HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\n",
+            b"x",
+            Duration::from_secs(30),
+        )
+        .await;
+        let config = test_config("proxy.upstream_idle_timeout_secs = 1");
+
+        let started = tokio::time::Instant::now();
+        let result = send_upstream(upstream_get(&upstream), &config, &timeouts(10)).await;
+        assert!(matches!(result, Err(UpstreamError::Timeout)));
+        assert!(started.elapsed() < 2 * SECOND);
+    }
+
+    #[tokio::test]
+    async fn oversized_response_is_rejected() {
+        let upstream = trickling_upstream(
+            "HTTP/1.1 200 OK\r\nContent-Length: 4096\r\n\r\n",
+            &[b'x'; 512],
+            Duration::ZERO,
+        )
+        .await;
+        let config = test_config("limits.max_response_size = 1024");
+
+        let result = send_upstream(upstream_get(&upstream), &config, &timeouts(5)).await;
+        assert!(matches!(result, Err(UpstreamError::Failed(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_upload_past_path_timeout_succeeds_with_idle_timeout() {
+        let config = test_config("limits.body_idle_timeout_secs = 2");
+        let (_, body) = read_body(trickle(5, SECOND, false), &config, 3 * SECOND)
+            .await
+            .unwrap_or_else(|r| panic!("rejected with {}", r.status()));
+        assert_eq!(body.len(), 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_upload_fails_on_idle_timeout() {
+        let config = test_config("limits.body_idle_timeout_secs = 2");
+        let response = read_body(trickle(1, SECOND, true), &config, 30 * SECOND).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn endless_trickle_is_capped_at_twice_the_path_timeout() {
+        let config = test_config("limits.body_idle_timeout_secs = 2");
+        let started = tokio::time::Instant::now();
+        let response = read_body(trickle(1000, SECOND, false), &config, 3 * SECOND)
+            .await
+            .unwrap_err();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(started.elapsed(), 6 * SECOND);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_upload_fails_without_idle_timeout() {
+        let config = test_config("");
+        let response = read_body(trickle(5, SECOND, false), &config, 3 * SECOND).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    fn redirect(location: &str) -> Response<Full<Bytes>> {
+        Response::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header(hyper::header::LOCATION, location)
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    }
+
+    fn request_parts(uri: &str) -> hyper::http::request::Parts {
+        let req = Request::get(uri).header("Host", "example.com").body(()).unwrap();
+        req.into_parts().0
+    }
+
+    #[test]
+    fn redirect_to_the_same_path_is_a_loop() {
+        let config = test_config("");
+        let parts = request_parts("/docs?page=2");
+        assert!(redirects_to_itself(&redirect("/docs?page=2"), &parts, &config));
+        assert!(redirects_to_itself(&redirect("https://example.com/docs?page=2"), &parts, &config));
+        assert!(redirects_to_itself(&redirect("http://127.0.0.1:9/docs?page=2"), &parts, &config));
+        assert!(!redirects_to_itself(&redirect("/docs?page=3"), &parts, &config));
+        assert!(!redirects_to_itself(&redirect("https://other.example/docs?page=2"), &parts, &config));
+    }
+
+    #[test]
+    fn scheme_upgrade_is_not_a_loop() {
+        let config = test_config("proxy.forwarded_proto = \"http\"");
+        let parts = request_parts("/docs");
+        assert!(!redirects_to_itself(&redirect("https://example.com/docs"), &parts, &config));
+        assert!(redirects_to_itself(&redirect("http://example.com/docs"), &parts, &config));
+    }
+
+    #[test]
+    fn location_is_compared_after_trailing_slash_normalization() {
+        let config = test_config("proxy.trailing_slash = \"remove\"");
+        let parts = request_parts("/docs");
+        assert!(redirects_to_itself(&redirect("/docs/"), &parts, &config));
+        assert!(redirects_to_itself(&redirect("https://example.com/docs/"), &parts, &config));
+
+        let config = test_config("");
+        assert!(!redirects_to_itself(&redirect("/docs/"), &parts, &config));
+    }
+
+    #[test]
+    fn repeated_headers_are_joined() {
+        let mut headers = HeaderMap::new();
+        headers.append("accept", "text/html".parse().unwrap());
+        headers.append("accept", "application/json".parse().unwrap());
+        headers.append("cookie", "a=1".parse().unwrap());
+        headers.append("cookie", "b=2".parse().unwrap());
+        headers.append("x-single", "kept".parse().unwrap());
+        merge_duplicate_headers(&mut headers);
+
+        assert_eq!(headers.get_all("accept").iter().count(), 1);
+        assert_eq!(headers["accept"], "text/html, application/json");
+        assert_eq!(headers["cookie"], "a=1; b=2");
+        assert_eq!(headers["x-single"], "kept");
+    }
+
+    async fn upstream_head(config: &Config) -> String {
+        let (upstream, head) = recording_upstream().await;
+        let req = Request::get(format!("{upstream}/"))
+            .header("x-request-source", "test")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        assert!(send_upstream(req, config, &timeouts(5)).await.is_ok());
+        head.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn header_names_are_title_cased_when_configured() {
+        let head = upstream_head(&test_config("proxy.normalize_header_case = \"title_case\"")).await;
+        assert!(head.contains("\r\nX-Request-Source: test\r\n"), "{head}");
+
+        let head = upstream_head(&test_config("")).await;
+        assert!(head.contains("\r\nx-request-source: test\r\n"), "{head}");
+    }
+
+    /// Accept one connection on a local port and never answer on it.
+    async fn silent_upstream() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        upstream
+    }
+
+    #[tokio::test]
+    async fn silent_upstream_fails_on_ttfb_timeout() {
+        let upstream = silent_upstream().await;
+        let config = test_config("proxy.ttfb_timeout_secs = 1");
+
+        let started = tokio::time::Instant::now();
+        let result = send_upstream(upstream_get(&upstream), &config, &timeouts(10)).await;
+        assert!(matches!(result, Err(UpstreamError::Timeout)));
+        assert!(started.elapsed() < 2 * SECOND);
+    }
+
+    #[tokio::test]
+    async fn slow_body_after_prompt_headers_outlives_ttfb_timeout() {
+        let upstream = trickling_upstream(
+            "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n",
+            b"x",
+            Duration::from_millis(600),
+        )
+        .await;
+        let config = test_config("proxy.ttfb_timeout_secs = 1");
+
+        let response = send_upstream(upstream_get(&upstream), &config, &timeouts(10)).await;
+        let body = response.ok().unwrap().into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"xxx");
+    }
+
+    #[test]
+    fn head_size_matches_the_wire_format() {
+        let req = Request::get("/abc?q=1").header("host", "a").header("accept", "*/*").body(()).unwrap();
+        let wire = "GET /abc?q=1 HTTP/1.1\r\nhost: a\r\naccept: */*\r\n";
+        assert_eq!(head_size(&req), wire.len() as u64);
+    }
+
+    fn post(body: &'static str, header_bytes: usize) -> Request<Full<Bytes>> {
+        Request::post("/upload")
+            .header("x-padding", "p".repeat(header_bytes))
+            .body(Full::new(Bytes::from_static(body.as_bytes())))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn headers_and_body_share_the_request_size_limit() {
+        let config = test_config("limits.max_request_size = 100");
+
+        assert!(read_body(post("small", 10), &config, SECOND).await.is_ok());
+        let response = read_body(post("small", 60), &config, SECOND).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let response = read_body(post("", 200), &config, SECOND).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    fn normalized(uri: &str, config: &Config) -> String {
+        let mut req = Request::get(uri).body(()).unwrap();
+        normalize_trailing_slash(&mut req, config);
+        req.uri().to_string()
+    }
+
+    #[test]
+    fn trailing_slash_is_added_or_removed_keeping_the_query() {
+        let add = test_config("proxy.trailing_slash = \"add\"");
+        assert_eq!(normalized("/docs?page=2", &add), "/docs/?page=2");
+        assert_eq!(normalized("/docs/", &add), "/docs/");
+
+        let remove = test_config("proxy.trailing_slash = \"remove\"");
+        assert_eq!(normalized("/docs//?page=2", &remove), "/docs?page=2");
+        assert_eq!(normalized("/docs", &remove), "/docs");
+
+        assert_eq!(normalized("/docs/", &test_config("")), "/docs/");
+    }
+
+    #[test]
+    fn root_files_and_exempt_paths_keep_their_slash() {
+        let add = test_config("proxy.trailing_slash = \"add\"");
+        assert_eq!(normalized("/", &add), "/");
+        assert_eq!(normalized("/static/app.js", &add), "/static/app.js");
+        assert_eq!(normalized("/.well-known/acme-challenge/token", &add), "/.well-known/acme-challenge/token");
+
+        let remove = test_config("proxy.trailing_slash = \"remove\"");
+        assert_eq!(normalized("/", &remove), "/");
+        assert_eq!(normalized("/.well-known/", &remove), "/.well-known/");
+    }
+
+    fn framing(pairs: &[(&'static str, &'static str)]) -> bool {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        ambiguous_framing(&headers)
+    }
+
+    #[test]
+    fn conflicting_framing_headers_are_ambiguous() {
+        assert!(framing(&[("content-length", "10"), ("transfer-encoding", "chunked")]));
+        assert!(framing(&[("content-length", "10"), ("content-length", "11")]));
+        assert!(framing(&[("content-length", "10, 11")]));
+    }
+
+    #[test]
+    fn consistent_framing_headers_are_not_ambiguous() {
+        assert!(!framing(&[]));
+        assert!(!framing(&[("content-length", "10")]));
+        assert!(!framing(&[("transfer-encoding", "chunked")]));
+        assert!(!framing(&[("content-length", "10"), ("content-length", " 10")]));
+        assert!(!framing(&[("content-length", "10, 10")]));
+    }
+
+    #[tokio::test]
+    async fn chunked_request_is_forwarded_with_content_length() {
+        let (upstream, request) = recording_upstream().await;
+        let config = test_config(&format!("proxy.upstream = \"{upstream}\""));
+        let chunks = ["hel", "lo"].map(|chunk| Ok(Frame::data(Bytes::from_static(chunk.as_bytes()))));
+        let body: Pin<Box<dyn Stream<Item = Result<_, Infallible>> + Send>> = Box::pin(stream::iter(chunks));
+        let req = Request::post("/upload")
+            .header("transfer-encoding", "chunked")
+            .body(StreamBody::new(body))
+            .unwrap();
+
+        let response = forward(req, &config, "127.0.0.1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let request = request.await.unwrap();
+        assert!(request.contains("\r\ncontent-length: 5\r\n"), "{request}");
+        assert!(!request.contains("transfer-encoding"), "{request}");
+        assert!(request.ends_with("\r\n\r\nhello"), "{request}");
+    }
+
+    #[tokio::test]
+    async fn chunked_response_is_reframed_with_content_length() {
+        let (upstream, _) = stub_upstream(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n",
+        )
+        .await;
+        let config = test_config("");
+
+        let response = send_upstream(upstream_get(&upstream), &config, &timeouts(5)).await.ok().unwrap();
+        assert_eq!(response.headers()[hyper::header::CONTENT_LENGTH], "5");
+        assert!(!response.headers().contains_key(hyper::header::TRANSFER_ENCODING));
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"hello");
+    }
+
+    #[tokio::test]
+    async fn response_with_length_and_chunked_encoding_is_a_bad_gateway() {
+        let (upstream, _) = stub_upstream(
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        )
+        .await;
+        let config = test_config(&format!("proxy.upstream = \"{upstream}\""));
+
+        let response = forward(upstream_get(""), &config, "127.0.0.1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn body_without_content_type_gets_the_default() {
+        let (upstream, _) = stub_upstream("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi").await;
+        let config = test_config(
+            "proxy.enforce_content_type = true\nproxy.default_content_type = \"text/plain\"",
+        );
+
+        let response = send_upstream(upstream_get(&upstream), &config, &timeouts(5)).await.ok().unwrap();
+        assert_eq!(response.headers()[hyper::header::CONTENT_TYPE], "text/plain");
+    }
+
+    fn override_headers(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(METHOD_OVERRIDE_HEADER, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn post_may_be_overridden_to_delete() {
+        let mut headers = override_headers("delete");
+        assert_eq!(apply_method_override(Method::POST, &mut headers), Method::DELETE);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn other_methods_and_targets_are_not_overridden() {
+        let mut headers = override_headers("DELETE");
+        assert_eq!(apply_method_override(Method::GET, &mut headers), Method::GET);
+        assert!(headers.is_empty());
+
+        let mut headers = override_headers("TRACE");
+        assert_eq!(apply_method_override(Method::POST, &mut headers), Method::POST);
+        assert!(headers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn overridden_request_is_forwarded_with_the_new_method() {
+        let (upstream, request) = recording_upstream().await;
+        let config = test_config(&format!(
+            "proxy.upstream = \"{upstream}\"\nproxy.honor_method_override = true"
+        ));
+        let req = Request::post("/items/1")
+            .header(METHOD_OVERRIDE_HEADER, "DELETE")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+
+        forward(req, &config, "127.0.0.1").await.unwrap();
+        let request = request.await.unwrap();
+        assert!(request.starts_with("DELETE /items/1 HTTP/1.1\r\n"), "{request}");
+        assert!(!request.contains(METHOD_OVERRIDE_HEADER), "{request}");
+    }
+
+    #[tokio::test]
+    async fn overridden_request_gets_the_timeouts_of_the_new_method() {
+        let upstream = silent_upstream().await;
+        let config = test_config(&format!(
+            r#"
+            proxy.upstream = "{upstream}"
+            proxy.honor_method_override = true
+
+            [[timeout_override]]
+            path = "/items"
+            methods = ["DELETE"]
+            timeout_secs = 1
+            "#
+        ));
+        let req = Request::post("/items/1")
+            .header(METHOD_OVERRIDE_HEADER, "DELETE")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+
+        let started = tokio::time::Instant::now();
+        let response = forward(req, &config, "127.0.0.1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(started.elapsed() < 2 * SECOND);
+    }
+
+    const TRUNCATED: &str = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello";
+
+    #[tokio::test]
+    async fn truncated_response_is_a_bad_gateway() {
+        let (upstream, _) = stub_upstream(TRUNCATED).await;
+        let config = test_config(&format!("proxy.upstream = \"{upstream}\""));
+
+        let response = forward(upstream_get(""), &config, "127.0.0.1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn truncated_response_is_relayed_short_when_configured() {
+        let (upstream, _) = stub_upstream(TRUNCATED).await;
+        let config = test_config(&format!(
+            "proxy.upstream = \"{upstream}\"\nproxy.truncated_response = \"forward\""
+        ));
+
+        let response = forward(upstream_get(""), &config, "127.0.0.1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[hyper::header::CONTENT_LENGTH], "5");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"hello");
+    }
+
+    /// A local address whose connects hang: the listener's accept queue
+    /// is full and nothing accepts, so further SYNs are dropped. The
+    /// listener and queued streams must outlive the test. Nominally
+    /// unroutable addresses aren't dependable in sandboxed networks.
+    async fn unreachable_upstream() -> (String, tokio::net::TcpListener, Vec<tokio::net::TcpStream>) {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut queued = Vec::new();
+        while let Ok(Ok(stream)) =
+            tokio::time::timeout(Duration::from_millis(200), tokio::net::TcpStream::connect(addr)).await
+        {
+            queued.push(stream);
+        }
+        (format!("http://{addr}"), listener, queued)
+    }
+
+    #[tokio::test]
+    async fn unreachable_upstream_fails_on_the_connect_timeout() {
+        let (upstream, _listener, _queued) = unreachable_upstream().await;
+        let config = test_config("");
+        let timeouts = Timeouts { total_secs: 10, connect_secs: Some(1), max_response_ms: None };
+
+        let started = tokio::time::Instant::now();
+        let result = send_upstream(upstream_get(&upstream), &config, &timeouts).await;
+        assert!(matches!(result, Err(UpstreamError::Failed(_))));
+        assert!(started.elapsed() < 2 * SECOND, "{:?}", started.elapsed());
+    }
+
+    async fn accept_encoding_sent_upstream(config: &str) -> String {
+        let (upstream, request) = recording_upstream().await;
+        let config = test_config(&format!("proxy.upstream = \"{upstream}\"\n{config}"));
+        let req = Request::get("/page")
+            .header("accept-encoding", "gzip, br")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+
+        forward(req, &config, "127.0.0.1").await.unwrap();
+        let request = request.await.unwrap();
+        let values: Vec<&str> =
+            request.lines().filter_map(|line| line.strip_prefix("accept-encoding: ")).collect();
+        values.join(", ")
+    }
+
+    #[tokio::test]
+    async fn accept_encoding_is_normalized_to_identity_when_stripped() {
+        let sent = accept_encoding_sent_upstream("proxy.upstream_accept_encoding = \"strip\"").await;
+        assert_eq!(sent, "identity");
+    }
+
+    #[tokio::test]
+    async fn accept_encoding_is_passed_through_by_default() {
+        assert_eq!(accept_encoding_sent_upstream("").await, "gzip, br");
+    }
+
+    async fn bodyless_request_sent_upstream(method: Method, config: &str) -> String {
+        let (upstream, request) = recording_upstream().await;
+        let config = test_config(&format!("proxy.upstream = \"{upstream}\"\n{config}"));
+        let req = Request::builder()
+            .method(method)
+            .uri("/items/1")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+
+        forward(req, &config, "127.0.0.1").await.unwrap();
+        request.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn empty_post_and_put_get_content_length_zero_when_normalized() {
+        for method in [Method::POST, Method::PUT] {
+            let request = bodyless_request_sent_upstream(method, "proxy.normalize_empty_body = true").await;
+            assert!(request.contains("\r\ncontent-length: 0\r\n"), "{request}");
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_bodies_get_no_content_length_by_default() {
+        let request = bodyless_request_sent_upstream(Method::POST, "").await;
+        assert!(!request.contains("content-length"), "{request}");
+        let request = bodyless_request_sent_upstream(Method::GET, "proxy.normalize_empty_body = true").await;
+        assert!(!request.contains("content-length"), "{request}");
+    }
+
+    #[tokio::test]
+    async fn inflated_request_body_is_sent_with_its_own_length() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"hello, hello, hello").unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let (upstream, request) = recording_upstream().await;
+        let config = test_config(&format!(
+            "proxy.upstream = \"{upstream}\"\nlimits.max_decompressed_size = 1024"
+        ));
+        let req = Request::post("/upload")
+            .header("content-encoding", "gzip")
+            .header("content-length", gzipped.len())
+            .body(Full::new(Bytes::from(gzipped)))
+            .unwrap();
+
+        forward(req, &config, "127.0.0.1").await.unwrap();
+        let request = request.await.unwrap();
+        assert!(request.contains("\r\ncontent-length: 19\r\n"), "{request}");
+        assert!(!request.contains("content-encoding"), "{request}");
+        assert!(request.ends_with("\r\n\r\nhello, hello, hello"), "{request}");
+    }
+
+    #[tokio::test]
+    async fn rewritten_response_body_is_sent_with_its_own_length() {
+        let (upstream, _) = stub_upstream(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 11\r\n\r\nhello world",
+        )
+        .await;
+        let config = test_config(
+            "[[response_rewrite]]\ncontent_type = \"text/plain\"\nfind = \"world\"\nreplace = \"there, world\"",
+        );
+
+        let response = send_upstream(upstream_get(&upstream), &config, &timeouts(5)).await.ok().unwrap();
+        assert_eq!(response.headers()[hyper::header::CONTENT_LENGTH], "18");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"hello there, world");
+    }
+
+    #[tokio::test]
+    async fn slow_upstream_fails_fast_on_the_response_time_target() {
+        let upstream = silent_upstream().await;
+        let config = test_config(&format!(
+            r#"
+            proxy.upstream = "{upstream}"
+
+            [[timeout_override]]
+            path = "/search"
+            timeout_secs = 10
+            max_response_ms = 200
+            "#
+        ));
+
+        let started = tokio::time::Instant::now();
+        let req = Request::get("/search?q=x").body(Full::new(Bytes::new())).unwrap();
+        let response = forward(req, &config, "127.0.0.1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(started.elapsed() < SECOND, "{:?}", started.elapsed());
+    }
+}
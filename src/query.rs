@@ -0,0 +1,237 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{Request, Response, StatusCode, Uri};
+use std::collections::HashMap;
+use tracing::{debug, warn};
+
+use crate::config::{LimitsConfig, QueryParam, QueryParamKind, QuerySchemaRule, UnknownQueryParams};
+
+/// Per-path query string schemas, so fuzzed or misspelled parameters
+/// are caught before they reach Django. The first prefix match wins.
+pub struct QuerySchema {
+    rules: Vec<QuerySchemaRule>,
+}
+
+impl QuerySchema {
+    pub fn new(rules: &[QuerySchemaRule]) -> Self {
+        Self { rules: rules.to_vec() }
+    }
+
+    /// Validate the request's query string against the matching rule.
+    /// Returns Some(Response) with 400 if rejected. Unknown parameters
+    /// are removed from the URI when the rule strips them.
+    pub fn check<B>(&self, req: &mut Request<B>) -> Option<Response<Full<Bytes>>> {
+        let path = req.uri().path();
+        let rule = self.rules.iter().find(|rule| path.starts_with(&rule.path))?;
+        let query = req.uri().query().unwrap_or("");
+
+        let mut kept = Vec::new();
+        let mut stripped = false;
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (raw_name, raw_value) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = percent_decode(raw_name);
+
+            let param = match rule.param.iter().find(|p| p.name == name) {
+                Some(param) => param,
+                None if rule.unknown == UnknownQueryParams::Strip => {
+                    debug!(path = %rule.path, param = %name, "Stripping unknown query parameter");
+                    stripped = true;
+                    continue;
+                }
+                None => {
+                    warn!(path = %rule.path, param = %name, "Unknown query parameter");
+                    return Some(bad_request());
+                }
+            };
+
+            let value = percent_decode(raw_value);
+            if !value_matches(param, &value) {
+                warn!(path = %rule.path, param = %name, "Malformed query parameter");
+                return Some(bad_request());
+            }
+            kept.push((name, pair));
+        }
+
+        if let Some(missing) = rule
+            .param
+            .iter()
+            .find(|p| p.required && !kept.iter().any(|(name, _)| *name == p.name))
+        {
+            warn!(path = %rule.path, param = %missing.name, "Missing required query parameter");
+            return Some(bad_request());
+        }
+
+        if stripped {
+            let pairs: Vec<&str> = kept.iter().map(|(_, pair)| *pair).collect();
+            let path_and_query = if pairs.is_empty() {
+                path.to_string()
+            } else {
+                format!("{}?{}", path, pairs.join("&"))
+            };
+            let mut parts = req.uri().clone().into_parts();
+            parts.path_and_query = path_and_query.parse().ok();
+            if let Ok(uri) = Uri::from_parts(parts) {
+                *req.uri_mut() = uri;
+            }
+        }
+
+        None
+    }
+}
+
+/// Enforce limits.max_query_params and limits.max_repeated_param.
+/// Returns Some(Response) with 400 if either is exceeded.
+pub fn check_limits(query: Option<&str>, limits: &LimitsConfig) -> Option<Response<Full<Bytes>>> {
+    if limits.max_query_params.is_none() && limits.max_repeated_param.is_none() {
+        return None;
+    }
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let pairs = query.unwrap_or("").split('&').filter(|p| !p.is_empty());
+    for (index, pair) in pairs.enumerate() {
+        if limits.max_query_params.is_some_and(|max| index >= max) {
+            warn!(max = ?limits.max_query_params, "Too many query parameters");
+            return Some(bad_request());
+        }
+
+        let name = pair.split_once('=').map_or(pair, |(name, _)| name);
+        let count = counts.entry(percent_decode(name)).or_insert(0);
+        *count += 1;
+        if limits.max_repeated_param.is_some_and(|max| *count > max) {
+            warn!(param = %percent_decode(name), count = *count, "Query parameter repeated too often");
+            return Some(bad_request());
+        }
+    }
+
+    None
+}
+
+fn value_matches(param: &QueryParam, value: &str) -> bool {
+    match param.kind {
+        QueryParamKind::String => true,
+        QueryParamKind::Int => value.parse::<i64>().is_ok(),
+        QueryParamKind::Bool => matches!(value, "true" | "false" | "1" | "0"),
+        QueryParamKind::Enum => param.values.iter().any(|v| v == value),
+    }
+}
+
+/// Decode `%XX` escapes and `+` as used in query strings. Invalid
+/// escapes are kept literally.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit() =>
+            {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                out.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+                continue;
+            }
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn bad_request() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .header("Content-Length", "0")
+        .body(Full::new(Bytes::new()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn limits(overrides: &str) -> LimitsConfig {
+        test_config(overrides).limits
+    }
+
+    #[test]
+    fn no_limits_allow_anything() {
+        let limits = limits("");
+        assert!(check_limits(Some("a=1&a=2&a=3&b=4"), &limits).is_none());
+    }
+
+    #[test]
+    fn too_many_parameters_are_rejected() {
+        let limits = limits("limits.max_query_params = 3");
+        assert!(check_limits(Some("a=1&b=2&c=3"), &limits).is_none());
+        assert!(check_limits(Some("a=1&&b=2&c=3&"), &limits).is_none());
+        let response = check_limits(Some("a=1&b=2&c=3&d=4"), &limits).unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn repeated_parameters_are_counted_after_decoding() {
+        let limits = limits("limits.max_repeated_param = 2");
+        assert!(check_limits(Some("id=1&id=2&other=3"), &limits).is_none());
+        assert!(check_limits(Some("id=1&id=2&i%64=3"), &limits).is_some());
+        assert!(check_limits(Some("id&id&id"), &limits).is_some());
+    }
+
+    #[test]
+    fn percent_decode_handles_escapes_plus_and_invalid_sequences() {
+        assert_eq!(percent_decode("a%20b+c"), "a b c");
+        assert_eq!(percent_decode("%e2%9c%93"), "\u{2713}");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz%41"), "%zzA");
+    }
+
+    fn schema() -> QuerySchema {
+        let config = test_config(
+            r#"
+            [[query_schema]]
+            path = "/search"
+            param = [
+                { name = "q", required = true },
+                { name = "page", kind = "int" },
+                { name = "sort", kind = "enum", values = ["new", "top"] },
+            ]
+
+            [[query_schema]]
+            path = "/feed"
+            unknown = "strip"
+            param = [{ name = "since", kind = "int" }]
+            "#,
+        );
+        QuerySchema::new(&config.query_schema)
+    }
+
+    fn checked(uri: &str) -> Result<String, StatusCode> {
+        let mut req = Request::get(uri).body(()).unwrap();
+        match schema().check(&mut req) {
+            Some(response) => Err(response.status()),
+            None => Ok(req.uri().to_string()),
+        }
+    }
+
+    #[test]
+    fn schema_validates_kinds_and_required_parameters() {
+        assert_eq!(checked("/search?q=rust&page=2&sort=top"), Ok("/search?q=rust&page=2&sort=top".into()));
+        assert_eq!(checked("/search?q=a%20b"), Ok("/search?q=a%20b".into()));
+        assert_eq!(checked("/search?q=rust&page=two"), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(checked("/search?q=rust&sort=old"), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(checked("/search?page=2"), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(checked("/search?q=rust&debug=1"), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(checked("/other?anything=1"), Ok("/other?anything=1".into()));
+    }
+
+    #[test]
+    fn schema_strips_unknown_parameters_when_configured() {
+        assert_eq!(checked("/feed?since=5&utm_source=x"), Ok("/feed?since=5".into()));
+        assert_eq!(checked("/feed?utm_source=x"), Ok("/feed".into()));
+        assert_eq!(checked("/feed?since=soon"), Err(StatusCode::BAD_REQUEST));
+    }
+}
@@ -1,32 +1,123 @@
 use dashmap::DashMap;
 use governor::{Quota, RateLimiter};
-use governor::clock::DefaultClock;
+use governor::clock::{Clock, DefaultClock};
 use governor::state::{InMemoryState, NotKeyed};
-use hyper::{Response, StatusCode};
+use hyper::{HeaderMap, Method, Response, StatusCode};
 use http_body_util::Full;
 use bytes::Bytes;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::VecDeque;
 use std::net::IpAddr;
 use std::num::NonZeroU32;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tracing::{warn, error};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
 
-use crate::config::{RateLimitConfig, ErrorRedirects};
+use crate::algorithm::PathLimiter;
+use crate::challenge;
+use crate::config::{
+    ChallengeConfig, RateLimitConfig, ErrorRedirects, HistoryConfig, IpOverflowAction, RateLimitRule,
+};
 
 type Limiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock>;
 
 const BAN_DURATION: Duration = Duration::from_secs(300);
-const MAX_VIOLATIONS: u32 = 5;
+/// Violations that get an IP banned.
+pub const MAX_VIOLATIONS: u32 = 5;
 
 struct ViolationRecord {
     count: u32,
-    first_violation: Instant,
+    challenge_passed: bool,
+}
+
+/// Metadata about a single request, kept in the per-IP history.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEntry {
+    pub timestamp: u64,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    #[serde(skip)]
+    recorded_at: Instant,
+}
+
+/// What an in-flight request is counted against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ConcurrencyKey {
+    User(String),
+    Ip(IpAddr),
+}
+
+/// Holds a concurrency slot; released on drop, whichever way the
+/// request finishes.
+pub struct ConcurrencyGuard<'a> {
+    in_flight: &'a DashMap<ConcurrencyKey, usize>,
+    key: Option<ConcurrencyKey>,
+}
+
+impl Drop for ConcurrencyGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.in_flight.remove_if_mut(&key, |_, count| {
+                *count -= 1;
+                *count == 0
+            });
+        }
+    }
+}
+
+/// Request rate of a single client connection.
+pub struct ConnectionRate(Limiter);
+
+impl ConnectionRate {
+    /// The per-connection quota, built once at startup so a bad value
+    /// fails there rather than on the first connection.
+    pub fn quota(per_sec: u32) -> Quota {
+        let rps = NonZeroU32::new(per_sec)
+            .expect("max_requests_per_connection_per_sec must be > 0");
+        Quota::per_second(rps)
+    }
+
+    pub fn new(quota: Quota) -> Self {
+        Self(RateLimiter::direct(quota))
+    }
+
+    /// Returns true if the request is allowed.
+    pub fn check(&self) -> bool {
+        self.0.check().is_ok()
+    }
+
+    /// 429 that also makes hyper close the connection, dropping any
+    /// requests still pipelined behind it.
+    pub fn flood_response() -> Response<Full<Bytes>> {
+        Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header("Connection", "close")
+            .header("Content-Length", "0")
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    }
 }
 
 pub struct RateLimit {
     limiters: DashMap<IpAddr, Arc<Limiter>>,
     violations: DashMap<IpAddr, ViolationRecord>,
     banned: DashMap<IpAddr, Instant>,
+    history: DashMap<IpAddr, VecDeque<HistoryEntry>>,
+    history_config: Option<HistoryConfig>,
+    path_limiters: DashMap<(usize, IpAddr), Arc<PathLimiter>>,
+    resource_limiters: DashMap<(usize, String), Arc<PathLimiter>>,
+    rules: Vec<RateLimitRule>,
+    rule_patterns: Vec<Option<Regex>>,
+    in_flight: DashMap<ConcurrencyKey, usize>,
+    max_concurrent: Option<usize>,
+    global: Option<Limiter>,
+    global_exempt: Vec<IpAddr>,
+    max_tracked_ips: Option<usize>,
+    ip_overflow: IpOverflowAction,
+    overflow: Limiter,
+    challenge: Option<ChallengeConfig>,
     quota: Quota,
 }
 
@@ -39,10 +130,29 @@ impl RateLimit {
 
         let quota = Quota::per_minute(rpm).allow_burst(burst);
 
+        let global = config.global_rps.map(|rps| {
+            let rps = NonZeroU32::new(rps).expect("global_rps must be > 0");
+            RateLimiter::direct(Quota::per_second(rps))
+        });
+
         Self {
-            limiters: DashMap::new(),
-            violations: DashMap::new(),
-            banned: DashMap::new(),
+            limiters: sharded(config.shards),
+            violations: sharded(config.shards),
+            banned: sharded(config.shards),
+            history: sharded(config.shards),
+            history_config: config.history.clone(),
+            path_limiters: sharded(config.shards),
+            resource_limiters: sharded(config.shards),
+            rules: config.rule.clone(),
+            rule_patterns: config.rule.iter().map(|r| rule_pattern(&r.path)).collect(),
+            in_flight: sharded(config.shards),
+            max_concurrent: config.max_concurrent_per_user,
+            global,
+            global_exempt: config.global_rps_exempt.clone(),
+            max_tracked_ips: config.max_tracked_ips,
+            ip_overflow: config.ip_overflow,
+            overflow: RateLimiter::direct(quota),
+            challenge: config.challenge.clone(),
             quota,
         }
     }
@@ -50,6 +160,7 @@ impl RateLimit {
     pub fn check_rate_limit(
         &self,
         ip: IpAddr,
+        path: &str,
         _redirects: &ErrorRedirects,
     ) -> Option<Response<Full<Bytes>>> {
         if let Some(ban_expiry) = self.banned.get(&ip) {
@@ -60,41 +171,271 @@ impl RateLimit {
             }
         }
 
-        let limiter = self
-            .limiters
-            .entry(ip)
-            .or_insert_with(|| Arc::new(RateLimiter::direct(self.quota)))
-            .clone();
+        let tracked = self.limiters.get(&ip).map(|limiter| limiter.clone());
+        let limiter = match tracked {
+            Some(limiter) => limiter,
+            None if self.over_ip_cap() => return self.check_overflow(ip),
+            None => self
+                .limiters
+                .entry(ip)
+                .or_insert_with(|| Arc::new(RateLimiter::direct(self.quota)))
+                .clone(),
+        };
+
+        if limiter.check().is_err() {
+            return Some(self.violation(ip));
+        }
+
+        match self.match_rule(path) {
+            Some((index, Some(id))) => {
+                let resource_limiter = self
+                    .resource_limiters
+                    .entry((index, id.clone()))
+                    .or_insert_with(|| Arc::new(PathLimiter::new(&self.rules[index])))
+                    .clone();
+
+                // The resource is busy, not necessarily this client: no violation.
+                if !resource_limiter.check() {
+                    warn!(ip = %ip, rule = %self.rules[index].path, id = %id, "Resource rate limit exceeded");
+                    return Some(status_response(StatusCode::TOO_MANY_REQUESTS));
+                }
+            }
+            Some((index, None)) => {
+                let path_limiter = self
+                    .path_limiters
+                    .entry((index, ip))
+                    .or_insert_with(|| Arc::new(PathLimiter::new(&self.rules[index])))
+                    .clone();
+
+                if !path_limiter.check() {
+                    warn!(ip = %ip, rule = %self.rules[index].path, "Path rate limit exceeded");
+                    return Some(self.violation(ip));
+                }
+            }
+            None => {}
+        }
+
+        None
+    }
+
+    /// Per-path rule, first prefix match wins. For a rule with `{name}`
+    /// segments the captured values come back too.
+    fn match_rule(&self, path: &str) -> Option<(usize, Option<String>)> {
+        self.rules.iter().enumerate().find_map(|(index, rule)| {
+            match &self.rule_patterns[index] {
+                Some(pattern) => pattern.captures(path).map(|caps| {
+                    let id: Vec<&str> = caps.iter().skip(1).flatten().map(|m| m.as_str()).collect();
+                    (index, Some(id.join("/")))
+                }),
+                None => path.starts_with(&rule.path).then_some((index, None)),
+            }
+        })
+    }
+
+    /// The path of the rate_limit.rule a path matches, if any.
+    pub fn matched_rule(&self, path: &str) -> Option<&str> {
+        self.match_rule(path).map(|(index, _)| self.rules[index].path.as_str())
+    }
+
+    fn over_ip_cap(&self) -> bool {
+        self.max_tracked_ips
+            .is_some_and(|max| self.limiters.len() >= max)
+    }
+
+    /// Most IPs kept in per-IP maps that have no cap of their own.
+    fn ip_cap(&self) -> usize {
+        self.max_tracked_ips.unwrap_or(10_000)
+    }
+
+    /// Limit an IP that arrived while the limiter map is full. Nothing is
+    /// stored per IP here, so violations aren't counted and per-path
+    /// rules don't apply; that's the price of bounding memory.
+    fn check_overflow(&self, ip: IpAddr) -> Option<Response<Full<Bytes>>> {
+        match self.ip_overflow {
+            IpOverflowAction::Shared => {
+                if self.overflow.check().is_err() {
+                    warn!(ip = %ip, "Shared overflow rate limit exceeded");
+                    return Some(status_response(StatusCode::TOO_MANY_REQUESTS));
+                }
+                None
+            }
+            IpOverflowAction::Reject => {
+                warn!(ip = %ip, "Tracked IP limit reached, rejecting new IP");
+                Some(status_response(StatusCode::SERVICE_UNAVAILABLE))
+            }
+        }
+    }
+
+    /// Proxy-wide admission check, run after the per-IP limits.
+    /// Returns Some(Response) with 503 and Retry-After when over capacity.
+    /// Not counted as a violation: the client isn't misbehaving.
+    pub fn check_global(&self, ip: IpAddr) -> Option<Response<Full<Bytes>>> {
+        let limiter = self.global.as_ref()?;
+        if self.global_exempt.contains(&ip) {
+            return None;
+        }
 
         match limiter.check() {
             Ok(_) => None,
-            Err(_) => {
-                let should_ban = {
-                    let mut entry = self
-                        .violations
-                        .entry(ip)
-                        .or_insert_with(|| ViolationRecord {
-                            count: 0,
-                            first_violation: Instant::now(),
-                        });
-
-                    entry.count += 1;
-                    warn!(ip = %ip, violations = entry.count, "Rate limit exceeded");
-                    entry.count >= MAX_VIOLATIONS
-                };
-
-                if should_ban {
-                    let ban_until = Instant::now() + BAN_DURATION;
-                    self.banned.insert(ip, ban_until);
-                    error!(ip = %ip, duration_secs = BAN_DURATION.as_secs(), "IP banned");
-                    return Some(status_response(StatusCode::FORBIDDEN));
-                }
+            Err(not_until) => {
+                let wait = not_until.wait_time_from(DefaultClock::default().now());
+                let retry_after = wait.as_secs().max(1);
+                warn!(ip = %ip, retry_after_secs = retry_after, "Global request rate exceeded");
 
-                Some(status_response(StatusCode::TOO_MANY_REQUESTS))
+                Some(
+                    Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .header("Retry-After", retry_after)
+                        .header("Content-Length", "0")
+                        .body(Full::new(Bytes::new()))
+                        .unwrap(),
+                )
             }
         }
     }
 
+    /// Take a concurrency slot for the user (or the IP, for anonymous
+    /// requests). Returns None when the cap is reached.
+    pub fn acquire_concurrency(
+        &self,
+        user: Option<&str>,
+        ip: IpAddr,
+    ) -> Option<ConcurrencyGuard<'_>> {
+        let max = match self.max_concurrent {
+            Some(max) => max,
+            None => return Some(ConcurrencyGuard { in_flight: &self.in_flight, key: None }),
+        };
+
+        let key = match user {
+            Some(user) => ConcurrencyKey::User(user.to_string()),
+            None => ConcurrencyKey::Ip(ip),
+        };
+
+        let mut count = self.in_flight.entry(key.clone()).or_insert(0);
+        if *count >= max {
+            warn!(key = ?key, in_flight = *count, "Concurrent request limit reached");
+            return None;
+        }
+        *count += 1;
+        drop(count);
+
+        Some(ConcurrencyGuard { in_flight: &self.in_flight, key: Some(key) })
+    }
+
+    pub fn concurrency_limited_response() -> Response<Full<Bytes>> {
+        status_response(StatusCode::TOO_MANY_REQUESTS)
+    }
+
+    /// Count a rate-limit violation, banning the IP once it has too many.
+    fn violation(&self, ip: IpAddr) -> Response<Full<Bytes>> {
+        let (should_ban, should_challenge) = {
+            let mut entry = self
+                .violations
+                .entry(ip)
+                .or_insert_with(|| ViolationRecord {
+                    count: 0,
+                    challenge_passed: false,
+                });
+
+            entry.count += 1;
+            warn!(ip = %ip, violations = entry.count, "Rate limit exceeded");
+            let should_challenge = self.challenge.as_ref().is_some_and(|challenge| {
+                !entry.challenge_passed && entry.count >= challenge.after_violations
+            });
+            (entry.count >= MAX_VIOLATIONS, should_challenge)
+        };
+
+        if should_ban {
+            let ban_until = Instant::now() + BAN_DURATION;
+            self.banned.insert(ip, ban_until);
+            error!(ip = %ip, duration_secs = BAN_DURATION.as_secs(), "IP banned");
+            return status_response(StatusCode::FORBIDDEN);
+        }
+
+        if let (true, Some(config)) = (should_challenge, &self.challenge) {
+            warn!(ip = %ip, "Serving challenge");
+            return challenge::response(config, ip);
+        }
+
+        status_response(StatusCode::TOO_MANY_REQUESTS)
+    }
+
+    /// Credit an IP presenting a valid challenge cookie: its violation
+    /// count drops by after_violations, once per violation record, so a
+    /// solved challenge can't be replayed to dodge a ban indefinitely.
+    pub fn check_challenge(&self, ip: IpAddr, headers: &HeaderMap) {
+        let config = match &self.challenge {
+            Some(config) => config,
+            None => return,
+        };
+
+        let mut entry = match self.violations.get_mut(&ip) {
+            Some(entry) if !entry.challenge_passed => entry,
+            _ => return,
+        };
+        if entry.count < config.after_violations || !challenge::verify(config, ip, headers) {
+            return;
+        }
+
+        entry.challenge_passed = true;
+        entry.count = entry.count.saturating_sub(config.after_violations);
+        info!(ip = %ip, violations = entry.count, "Challenge passed");
+    }
+
+    /// Record a finished request in the IP's history ring buffer.
+    /// No-op unless `rate_limit.history` is configured.
+    pub fn record_request(&self, ip: IpAddr, method: &Method, path: &str, status: StatusCode) {
+        let config = match &self.history_config {
+            Some(config) if config.size > 0 => config,
+            _ => return,
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        // Past the cap, new IPs go unrecorded until cleanup makes room
+        if !self.history.contains_key(&ip) && self.history.len() >= self.ip_cap() {
+            return;
+        }
+
+        let mut entries = self.history.entry(ip).or_default();
+        while entries.len() >= config.size {
+            entries.pop_front();
+        }
+        entries.push_back(HistoryEntry {
+            timestamp,
+            method: method.to_string(),
+            path: path.to_string(),
+            status: status.as_u16(),
+            recorded_at: Instant::now(),
+        });
+    }
+
+    /// Recent requests from an IP, oldest first, within the retention window.
+    pub fn history_for(&self, ip: IpAddr) -> Vec<HistoryEntry> {
+        let retention = match &self.history_config {
+            Some(config) => Duration::from_secs(config.retention_secs),
+            None => return Vec::new(),
+        };
+
+        self.history
+            .get(&ip)
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|e| e.recorded_at.elapsed() < retention)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn history_enabled(&self) -> bool {
+        self.history_config.is_some()
+    }
+
     pub fn cleanup(&self) {
         let now = Instant::now();
 
@@ -109,17 +450,297 @@ impl RateLimit {
 
         self.violations.retain(|ip, _| self.banned.contains_key(ip));
 
+        if let Some(config) = &self.history_config {
+            let retention = Duration::from_secs(config.retention_secs);
+            self.history.retain(|_, entries| {
+                entries.retain(|e| e.recorded_at.elapsed() < retention);
+                !entries.is_empty()
+            });
+            if self.history.len() >= self.ip_cap() {
+                warn!("Request history reached its IP cap, clearing");
+                self.history.clear();
+            }
+        }
+
         if self.limiters.len() > 10_000 {
             warn!("Rate limiter map exceeded 10k entries, clearing");
             self.limiters.clear();
+        } else if self.over_ip_cap() {
+            warn!("Rate limiter map reached max_tracked_ips, clearing");
+            self.limiters.clear();
         }
+
+        if self.path_limiters.len() > 10_000 {
+            warn!("Path rate limiter map exceeded 10k entries, clearing");
+            self.path_limiters.clear();
+        }
+
+        if self.resource_limiters.len() > 10_000 {
+            warn!("Resource rate limiter map exceeded 10k entries, clearing");
+            self.resource_limiters.clear();
+        }
+    }
+}
+
+fn sharded<K: Eq + std::hash::Hash, V>(shards: Option<usize>) -> DashMap<K, V> {
+    match shards {
+        Some(shards) => {
+            assert!(
+                shards > 1 && shards.is_power_of_two(),
+                "rate_limit.shards must be a power of two greater than 1"
+            );
+            DashMap::with_shard_amount(shards)
+        }
+        None => DashMap::new(),
     }
 }
 
+/// Compile a rule path with `{name}` segments into a prefix regex that
+/// captures each one. Plain paths return None and match by prefix.
+fn rule_pattern(path: &str) -> Option<Regex> {
+    if !path.contains('{') {
+        return None;
+    }
+
+    let mut pattern = String::from("^");
+    let mut rest = path;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        pattern.push_str(&regex::escape(&rest[..start]));
+        pattern.push_str("([^/]+)");
+        rest = &rest[end + 1..];
+    }
+    pattern.push_str(&regex::escape(rest));
+
+    Some(Regex::new(&pattern).expect("Failed to compile rate_limit.rule path pattern"))
+}
+
 fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
     Response::builder()
         .status(status)
         .header("Content-Length", "0")
         .body(Full::new(Bytes::new()))
         .unwrap()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_rate_allows_quota_then_rejects() {
+        let rate = ConnectionRate::new(ConnectionRate::quota(3));
+        assert!((0..3).all(|_| rate.check()));
+        assert!(!rate.check());
+    }
+
+    #[test]
+    #[should_panic(expected = "must be > 0")]
+    fn zero_connection_rate_fails_when_building_the_quota() {
+        ConnectionRate::quota(0);
+    }
+
+    fn captures(pattern: &Regex, path: &str) -> Option<Vec<String>> {
+        let caps = pattern.captures(path)?;
+        Some(caps.iter().skip(1).map(|m| m.unwrap().as_str().to_string()).collect())
+    }
+
+    #[test]
+    fn plain_rule_paths_have_no_pattern() {
+        assert!(rule_pattern("/api/users").is_none());
+    }
+
+    #[test]
+    fn rule_pattern_captures_each_segment_as_a_prefix_match() {
+        let pattern = rule_pattern("/api/users/{id}/posts/{post}").unwrap();
+        assert_eq!(captures(&pattern, "/api/users/42/posts/7"), Some(vec!["42".into(), "7".into()]));
+        assert_eq!(captures(&pattern, "/api/users/42/posts/7/comments"), Some(vec!["42".into(), "7".into()]));
+        assert_eq!(captures(&pattern, "/api/users//posts/7"), None);
+        assert_eq!(captures(&pattern, "/v2/api/users/42/posts/7"), None);
+    }
+
+    #[test]
+    fn rule_pattern_escapes_literal_parts() {
+        let pattern = rule_pattern("/v1.0/{id}").unwrap();
+        assert!(pattern.is_match("/v1.0/3"));
+        assert!(!pattern.is_match("/v1x0/3"));
+
+        let unterminated = rule_pattern("/a/{id").unwrap();
+        assert!(unterminated.is_match("/a/{id"));
+        assert!(!unterminated.is_match("/a/3"));
+    }
+
+    fn rate_limit(overrides: &str) -> RateLimit {
+        RateLimit::new(&crate::config::test_config(overrides).rate_limit)
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([203, 0, 113, last])
+    }
+
+    #[test]
+    fn history_keeps_the_latest_requests_per_ip() {
+        let limiter = rate_limit("rate_limit.history = { size = 3, retention_secs = 60 }");
+        for n in 0..5 {
+            limiter.record_request(ip(1), &Method::GET, &format!("/page/{n}"), StatusCode::OK);
+        }
+        limiter.record_request(ip(2), &Method::POST, "/login", StatusCode::UNAUTHORIZED);
+
+        let paths: Vec<String> = limiter.history_for(ip(1)).into_iter().map(|e| e.path).collect();
+        assert_eq!(paths, ["/page/2", "/page/3", "/page/4"]);
+        let other = limiter.history_for(ip(2));
+        assert_eq!((other[0].method.as_str(), other[0].status), ("POST", 401));
+    }
+
+    #[test]
+    fn history_outside_retention_is_hidden() {
+        let limiter = rate_limit("rate_limit.history = { size = 3, retention_secs = 0 }");
+        limiter.record_request(ip(1), &Method::GET, "/", StatusCode::OK);
+        assert!(limiter.history_for(ip(1)).is_empty());
+    }
+
+    #[test]
+    fn history_tracks_at_most_max_tracked_ips() {
+        let limiter = rate_limit(
+            "rate_limit.history = { size = 3, retention_secs = 60 }\nrate_limit.max_tracked_ips = 2",
+        );
+        for last in 1..=3 {
+            limiter.record_request(ip(last), &Method::GET, "/", StatusCode::OK);
+        }
+        assert_eq!(limiter.history.len(), 2);
+        assert!(limiter.history_for(ip(3)).is_empty());
+
+        // Known IPs keep recording while new ones are turned away
+        limiter.record_request(ip(1), &Method::GET, "/again", StatusCode::OK);
+        assert_eq!(limiter.history_for(ip(1)).len(), 2);
+
+        limiter.cleanup();
+        assert!(limiter.history.is_empty());
+    }
+
+    #[test]
+    fn concurrent_requests_past_the_cap_are_refused() {
+        let limiter = rate_limit("rate_limit.max_concurrent_per_user = 2");
+        let first = limiter.acquire_concurrency(Some("alice"), ip(1)).unwrap();
+        let _second = limiter.acquire_concurrency(Some("alice"), ip(2)).unwrap();
+        assert!(limiter.acquire_concurrency(Some("alice"), ip(1)).is_none());
+        assert_eq!(
+            RateLimit::concurrency_limited_response().status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        // Other users and anonymous IPs have their own slots
+        assert!(limiter.acquire_concurrency(Some("bob"), ip(1)).is_some());
+        assert!(limiter.acquire_concurrency(None, ip(1)).is_some());
+
+        drop(first);
+        assert!(limiter.acquire_concurrency(Some("alice"), ip(1)).is_some());
+    }
+
+    #[test]
+    fn released_slots_leave_no_entries_behind() {
+        let limiter = rate_limit("rate_limit.max_concurrent_per_user = 1");
+        let guard = limiter.acquire_concurrency(None, ip(1)).unwrap();
+        assert!(limiter.acquire_concurrency(None, ip(1)).is_none());
+        drop(guard);
+        assert!(limiter.in_flight.is_empty());
+    }
+
+    #[test]
+    fn each_resource_id_gets_its_own_bucket() {
+        let limiter = rate_limit(
+            r#"
+            [[rate_limit.rule]]
+            path = "/users/{id}"
+            requests_per_minute = 2
+            algorithm = "fixed_window"
+            "#,
+        );
+        let redirects = crate::config::test_config("").error_redirects;
+        let status = |ip, path| limiter.check_rate_limit(ip, path, &redirects).map(|r| r.status());
+
+        assert_eq!(status(ip(1), "/users/1"), None);
+        assert_eq!(status(ip(2), "/users/1/posts"), None);
+        // The bucket belongs to the resource, whichever IP asks
+        assert_eq!(status(ip(3), "/users/1"), Some(StatusCode::TOO_MANY_REQUESTS));
+        assert_eq!(status(ip(1), "/users/2"), None);
+    }
+
+    #[test]
+    fn global_cap_blocks_distinct_ips_together() {
+        let limiter = rate_limit("rate_limit.global_rps = 3\nrate_limit.global_rps_exempt = [\"203.0.113.99\"]");
+        for last in 1..=3 {
+            assert!(limiter.check_global(ip(last)).is_none());
+        }
+
+        let response = limiter.check_global(ip(4)).unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "1");
+        assert!(limiter.check_global(ip(99)).is_none());
+    }
+
+    #[test]
+    fn untracked_ips_share_one_limiter_past_the_cap() {
+        let limiter = rate_limit("rate_limit.max_tracked_ips = 2");
+        let redirects = crate::config::test_config("").error_redirects;
+        let status = |ip| limiter.check_rate_limit(ip, "/", &redirects).map(|r| r.status());
+
+        assert_eq!(status(ip(1)), None);
+        assert_eq!(status(ip(2)), None);
+        // burst_size is 10: ten new IPs fit in the shared bucket together.
+        for last in 3..13 {
+            assert_eq!(status(ip(last)), None);
+        }
+        assert_eq!(status(ip(13)), Some(StatusCode::TOO_MANY_REQUESTS));
+        assert_eq!(status(ip(1)), None);
+    }
+
+    #[test]
+    fn untracked_ips_are_rejected_past_the_cap_when_configured() {
+        let limiter = rate_limit("rate_limit.max_tracked_ips = 2\nrate_limit.ip_overflow = \"reject\"");
+        let redirects = crate::config::test_config("").error_redirects;
+        let status = |ip| limiter.check_rate_limit(ip, "/", &redirects).map(|r| r.status());
+
+        assert_eq!(status(ip(1)), None);
+        assert_eq!(status(ip(2)), None);
+        assert_eq!(status(ip(3)), Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(status(ip(2)), None);
+    }
+
+    /// Hammer the per-IP maps from several threads with a few shards and
+    /// with many. Timing-dependent, so run by hand:
+    /// `cargo test --release shard_count -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn shard_count_contention() {
+        const THREADS: u8 = 8;
+        const CHECKS: u32 = 50_000;
+        let redirects = crate::config::test_config("").error_redirects;
+
+        let run = |shards: usize| {
+            let limiter = rate_limit(&format!("rate_limit.shards = {shards}"));
+            let started = std::time::Instant::now();
+            std::thread::scope(|scope| {
+                for thread in 0..THREADS {
+                    let (limiter, redirects) = (&limiter, &redirects);
+                    scope.spawn(move || {
+                        for n in 0..CHECKS {
+                            let [_, _, hi, lo] = (n % 4096).to_be_bytes();
+                            let ip = IpAddr::from([10, thread, hi, lo]);
+                            limiter.check_rate_limit(ip, "/", redirects);
+                        }
+                    });
+                }
+            });
+            started.elapsed()
+        };
+
+        let few = run(2);
+        let many = run(64);
+        eprintln!("2 shards: {few:?}, 64 shards: {many:?}");
+        assert!(many < few * 3 / 2, "64 shards slower than 2: {many:?} vs {few:?}");
+    }
+}
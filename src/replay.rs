@@ -0,0 +1,206 @@
+use bytes::Bytes;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use http_body_util::Full;
+use hyper::{HeaderMap, Response, StatusCode};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::config::ReplayProtectionConfig;
+
+const NONCE_HEADER: &str = "x-nonce";
+const TIMESTAMP_HEADER: &str = "x-timestamp";
+const MAX_NONCE_LEN: usize = 128;
+/// How often a full store may be swept inline; between sweeps new
+/// nonces get 503 without paying for a scan of the whole store.
+const FULL_STORE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Rejects replays of captured requests on protected paths. Each request
+/// must carry a fresh X-Timestamp (Unix seconds) and an X-Nonce not seen
+/// while that timestamp could still be accepted.
+pub struct ReplayGuard {
+    paths: Vec<String>,
+    tolerance_secs: u64,
+    max_nonces: usize,
+    missing_status: StatusCode,
+    seen: DashMap<String, Instant>,
+    last_sweep: Mutex<Option<Instant>>,
+}
+
+impl ReplayGuard {
+    pub fn new(config: &ReplayProtectionConfig) -> Self {
+        let missing_status = StatusCode::from_u16(config.missing_status)
+            .expect("replay_protection.missing_status is not a valid status code");
+
+        Self {
+            paths: config.paths.clone(),
+            tolerance_secs: config.tolerance_secs,
+            max_nonces: config.max_nonces,
+            missing_status,
+            seen: DashMap::new(),
+            last_sweep: Mutex::new(None),
+        }
+    }
+
+    /// A nonce only has to be remembered while a request carrying it
+    /// could still pass the timestamp check.
+    fn nonce_ttl(&self) -> Duration {
+        Duration::from_secs(self.tolerance_secs * 2)
+    }
+
+    /// Check a request's nonce and timestamp.
+    /// Returns Some(Response) if rejected: missing_status for missing or
+    /// malformed headers, 401 for a stale timestamp or a reused nonce,
+    /// 503 if the nonce store is full.
+    pub fn check(&self, path: &str, headers: &HeaderMap) -> Option<Response<Full<Bytes>>> {
+        if !self.paths.iter().any(|prefix| path.starts_with(prefix.as_str())) {
+            return None;
+        }
+
+        let nonce = headers.get(NONCE_HEADER).and_then(|v| v.to_str().ok());
+        let timestamp = headers
+            .get(TIMESTAMP_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok());
+
+        let (nonce, timestamp) = match (nonce, timestamp) {
+            (Some(nonce), Some(timestamp)) if !nonce.is_empty() && nonce.len() <= MAX_NONCE_LEN => {
+                (nonce, timestamp)
+            }
+            _ => {
+                warn!(path = path, "Missing or malformed replay protection headers");
+                return Some(status_response(self.missing_status));
+            }
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        if now.abs_diff(timestamp) > self.tolerance_secs {
+            warn!(path = path, timestamp = timestamp, "Request timestamp outside tolerance");
+            return Some(status_response(StatusCode::UNAUTHORIZED));
+        }
+
+        if self.seen.len() >= self.max_nonces {
+            self.sweep_if_due();
+            if self.seen.len() >= self.max_nonces {
+                warn!(max_nonces = self.max_nonces, "Nonce store full");
+                return Some(status_response(StatusCode::SERVICE_UNAVAILABLE));
+            }
+        }
+
+        match self.seen.entry(nonce.to_string()) {
+            Entry::Occupied(mut seen) => {
+                if seen.get().elapsed() < self.nonce_ttl() {
+                    warn!(path = path, nonce = nonce, "Replayed nonce rejected");
+                    return Some(status_response(StatusCode::UNAUTHORIZED));
+                }
+                seen.insert(Instant::now());
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(Instant::now());
+            }
+        }
+
+        None
+    }
+
+    /// Sweep expired nonces, at most once per FULL_STORE_SWEEP_INTERVAL.
+    fn sweep_if_due(&self) {
+        {
+            let mut last_sweep = self.last_sweep.lock().unwrap();
+            if last_sweep.is_some_and(|at| at.elapsed() < FULL_STORE_SWEEP_INTERVAL) {
+                return;
+            }
+            *last_sweep = Some(Instant::now());
+        }
+        self.cleanup();
+    }
+
+    pub fn cleanup(&self) {
+        let ttl = self.nonce_ttl();
+        self.seen.retain(|_, seen_at| seen_at.elapsed() < ttl);
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Length", "0")
+        .body(Full::new(Bytes::new()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn guard(overrides: &str) -> ReplayGuard {
+        let config = test_config(&format!("[replay_protection]\npaths = [\"/pay\"]\n{overrides}"));
+        ReplayGuard::new(config.replay_protection.as_ref().unwrap())
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn headers(nonce: &str, timestamp: u64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(NONCE_HEADER, nonce.parse().unwrap());
+        headers.insert(TIMESTAMP_HEADER, timestamp.to_string().parse().unwrap());
+        headers
+    }
+
+    fn status(guard: &ReplayGuard, headers: &HeaderMap) -> Option<StatusCode> {
+        guard.check("/pay/order", headers).map(|response| response.status())
+    }
+
+    #[test]
+    fn replayed_nonce_is_rejected() {
+        let guard = guard("");
+        let request = headers("abc", now());
+        assert_eq!(status(&guard, &request), None);
+        assert_eq!(status(&guard, &request), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(&guard, &headers("def", now())), None);
+    }
+
+    #[test]
+    fn stale_or_future_timestamp_is_rejected() {
+        let guard = guard("tolerance_secs = 60");
+        assert_eq!(status(&guard, &headers("old", now() - 120)), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(&guard, &headers("new", now() + 120)), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(&guard, &headers("ok", now() - 30)), None);
+    }
+
+    #[test]
+    fn missing_headers_get_the_configured_status() {
+        let guard = guard("missing_status = 401");
+        assert_eq!(status(&guard, &HeaderMap::new()), Some(StatusCode::UNAUTHORIZED));
+        assert!(guard.check("/public", &HeaderMap::new()).is_none());
+    }
+
+    #[test]
+    fn full_store_rejects_new_nonces() {
+        let guard = guard("max_nonces = 2");
+        assert_eq!(status(&guard, &headers("a", now())), None);
+        assert_eq!(status(&guard, &headers("b", now())), None);
+        assert_eq!(status(&guard, &headers("c", now())), Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(status(&guard, &headers("a", now())), Some(StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    #[test]
+    fn full_store_is_swept_at_most_once_per_interval() {
+        let guard = guard("max_nonces = 1");
+        let expired = Instant::now() - guard.nonce_ttl() - Duration::from_secs(1);
+        guard.seen.insert("expired".to_string(), expired);
+
+        assert_eq!(status(&guard, &headers("a", now())), None);
+        guard.seen.insert("expired".to_string(), expired);
+        guard.seen.remove("a");
+        assert_eq!(status(&guard, &headers("b", now())), Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(guard.seen.contains_key("expired"));
+    }
+}
@@ -0,0 +1,138 @@
+use bytes::Bytes;
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::http::response::Parts;
+use tracing::{debug, warn};
+
+use crate::config::ResponseRewrite;
+
+/// Apply `[[response_rewrite]]` rules to a buffered upstream response.
+///
+/// Replacement is purely literal so a JSON body stays valid JSON. For
+/// JSON, both strings are matched and inserted in their JSON-escaped
+/// form, and when the search string has a `/` its `\/`-escaped form is
+/// replaced as well, since some encoders escape forward slashes in URLs.
+/// Content-Length is updated to match the rewritten body. Empty bodies
+/// (HEAD answers, 204, 304) are left alone, Content-Length included.
+pub fn apply(parts: &mut Parts, body: Bytes, rules: &[ResponseRewrite]) -> Bytes {
+    if body.is_empty() {
+        return body;
+    }
+
+    let content_type = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    let matching: Vec<&ResponseRewrite> = rules
+        .iter()
+        .filter(|rule| rule.content_type.eq_ignore_ascii_case(&content_type))
+        .collect();
+
+    if matching.is_empty() {
+        return body;
+    }
+
+    if parts.headers.contains_key(CONTENT_ENCODING) {
+        warn!(content_type = %content_type, "Skipping response rewrite of encoded body");
+        return body;
+    }
+
+    let mut text = match String::from_utf8(body.to_vec()) {
+        Ok(text) => text,
+        Err(_) => {
+            warn!(content_type = %content_type, "Skipping response rewrite of non-UTF-8 body");
+            return body;
+        }
+    };
+
+    let is_json = content_type == "application/json" || content_type.ends_with("+json");
+    for rule in matching {
+        if !is_json {
+            text = text.replace(&rule.find, &rule.replace);
+            continue;
+        }
+
+        let (find, replace) = (json_escape(&rule.find), json_escape(&rule.replace));
+        text = text.replace(&find, &replace);
+        if find.contains('/') {
+            text = text.replace(&find.replace('/', "\\/"), &replace.replace('/', "\\/"));
+        }
+    }
+
+    debug!(content_type = %content_type, "Rewrote response body");
+
+    let body = Bytes::from(text);
+    parts.headers.insert(CONTENT_LENGTH, body.len().into());
+    body
+}
+
+/// `s` as it appears inside a JSON string literal.
+fn json_escape(s: &str) -> String {
+    let quoted = serde_json::to_string(s).expect("a string always serializes");
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Response, StatusCode};
+
+    fn rule(find: &str, replace: &str) -> ResponseRewrite {
+        ResponseRewrite {
+            content_type: "application/json".to_string(),
+            find: find.to_string(),
+            replace: replace.to_string(),
+        }
+    }
+
+    fn json_parts(status: StatusCode, content_length: usize) -> Parts {
+        let (parts, _) = Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json; charset=utf-8")
+            .header(CONTENT_LENGTH, content_length)
+            .body(())
+            .unwrap()
+            .into_parts();
+        parts
+    }
+
+    fn rewrite(body: &str, rules: &[ResponseRewrite]) -> (String, Parts) {
+        let mut parts = json_parts(StatusCode::OK, body.len());
+        let body = apply(&mut parts, Bytes::from(body.to_string()), rules);
+        (String::from_utf8(body.to_vec()).unwrap(), parts)
+    }
+
+    #[test]
+    fn rewrites_urls_in_json_body() {
+        let body = r#"{"next":"http://django:8000/api/items?page=2","file":"http:\/\/django:8000\/media\/a.png"}"#;
+        let (text, parts) = rewrite(body, &[rule("http://django:8000", "https://api.example.com")]);
+
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(value["next"], "https://api.example.com/api/items?page=2");
+        assert_eq!(value["file"], "https://api.example.com/media/a.png");
+        assert_eq!(parts.headers[CONTENT_LENGTH], text.len().to_string().as_str());
+    }
+
+    #[test]
+    fn find_without_slash_is_replaced_once() {
+        let (text, _) = rewrite(r#"{"host":"django"}"#, &[rule("django", "django-internal")]);
+        assert_eq!(text, r#"{"host":"django-internal"}"#);
+    }
+
+    #[test]
+    fn replacement_is_json_escaped() {
+        let (text, _) = rewrite(r#"{"name":"PLACEHOLDER"}"#, &[rule("PLACEHOLDER", r#"say "hi""#)]);
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(value["name"], r#"say "hi""#);
+    }
+
+    #[test]
+    fn empty_body_keeps_content_length() {
+        let mut parts = json_parts(StatusCode::NOT_MODIFIED, 512);
+        let body = apply(&mut parts, Bytes::new(), &[rule("a", "b")]);
+        assert!(body.is_empty());
+        assert_eq!(parts.headers[CONTENT_LENGTH], "512");
+    }
+}
@@ -0,0 +1,128 @@
+use bytes::Bytes;
+use dashmap::DashMap;
+use http_body_util::Full;
+use hyper::{Response, StatusCode};
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tracing::warn;
+
+use crate::config::SessionOrderingConfig;
+
+/// Serializes requests that share a session header value, for legacy
+/// clients whose state breaks under concurrent or out-of-order handling.
+/// Requests queue in arrival order on a per-session async mutex.
+pub struct SessionOrdering {
+    header: String,
+    max_queue_depth: usize,
+    sessions: DashMap<String, (Arc<Mutex<()>>, usize)>,
+}
+
+/// Holds a session's turn; the next queued request runs once it drops.
+pub struct SessionGuard<'a> {
+    sessions: &'a DashMap<String, (Arc<Mutex<()>>, usize)>,
+    key: String,
+    turn: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for SessionGuard<'_> {
+    fn drop(&mut self) {
+        self.turn.take();
+        self.sessions.remove_if_mut(&self.key, |_, (_, count)| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+impl SessionOrdering {
+    pub fn new(config: &SessionOrderingConfig) -> Self {
+        Self {
+            header: config.header.to_ascii_lowercase(),
+            max_queue_depth: config.max_queue_depth,
+            sessions: DashMap::new(),
+        }
+    }
+
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+
+    /// Wait for the session's turn. Returns None when more than
+    /// max_queue_depth requests are already waiting behind the one in
+    /// flight.
+    pub async fn acquire(&self, session: &str) -> Option<SessionGuard<'_>> {
+        let lock = {
+            let mut entry = self
+                .sessions
+                .entry(session.to_string())
+                .or_insert_with(|| (Arc::new(Mutex::new(())), 0));
+            let (lock, count) = entry.value_mut();
+            if *count > self.max_queue_depth {
+                warn!(session = session, queued = *count - 1, "Session queue full");
+                return None;
+            }
+            *count += 1;
+            lock.clone()
+        };
+
+        // Built before waiting so a cancelled request still gives up its
+        // place in the count.
+        let mut guard = SessionGuard {
+            sessions: &self.sessions,
+            key: session.to_string(),
+            turn: None,
+        };
+        guard.turn = Some(lock.lock_owned().await);
+        Some(guard)
+    }
+
+    pub fn queue_full_response() -> Response<Full<Bytes>> {
+        status_response(StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Length", "0")
+        .body(Full::new(Bytes::new()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    fn ordering(max_queue_depth: usize) -> SessionOrdering {
+        SessionOrdering::new(&SessionOrderingConfig {
+            header: "X-Session-Id".to_string(),
+            max_queue_depth,
+        })
+    }
+
+    #[tokio::test]
+    async fn same_session_waits_for_its_turn() {
+        let ordering = ordering(4);
+        let first = ordering.acquire("abc").await.unwrap();
+
+        let mut second = Box::pin(ordering.acquire("abc"));
+        assert!((&mut second).now_or_never().is_none());
+        assert!(ordering.acquire("other").now_or_never().is_some());
+
+        drop(first);
+        assert!(second.now_or_never().flatten().is_some());
+    }
+
+    #[tokio::test]
+    async fn queue_beyond_max_depth_is_rejected() {
+        let ordering = ordering(1);
+        let _first = ordering.acquire("abc").await.unwrap();
+        let mut queued = Box::pin(ordering.acquire("abc"));
+        assert!((&mut queued).now_or_never().is_none());
+
+        assert!(ordering.acquire("abc").await.is_none());
+        drop(queued);
+        assert!(ordering.acquire("abc").now_or_never().is_none());
+    }
+}
@@ -1,6 +1,22 @@
+use hyper::Uri;
+use hyper_util::rt::TokioIo;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::time::{Instant, Sleep};
+use tracing::warn;
 use crate::config::Config;
 
+/// Pending Fast Open handshakes the kernel queues per listener.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+const FAST_OPEN_QUEUE: i32 = 256;
+const LISTEN_BACKLOG: u32 = 1024;
+
 pub struct TcpConfig<'a> {
     config: &'a Config,
 }
@@ -10,10 +26,9 @@ impl<'a> TcpConfig<'a> {
         Self { config }
     }
 
-    /// Get the timeout duration for a given request path.
-    pub fn timeout_for_path(&self, path: &str) -> Duration {
-        let secs = self.config.timeout_for_path(path);
-        Duration::from_secs(secs)
+    /// Get the timeout duration for a given request.
+    pub fn timeout_for(&self, method: &str, path: &str) -> Duration {
+        Duration::from_secs(self.config.timeouts(method, path, None).total_secs)
     }
 
     /// Get the default timeout duration.
@@ -21,3 +36,315 @@ impl<'a> TcpConfig<'a> {
         Duration::from_secs(self.config.limits.default_timeout_secs)
     }
 }
+
+/// Bind the proxy listener, optionally with TCP Fast Open so returning
+/// clients can send their request in the SYN.
+pub fn bind_listener(addr: SocketAddr, fast_open: bool) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    if fast_open {
+        set_fast_open(&socket, FastOpen::Listener);
+    }
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+/// Client connection wrapper that aborts slow readers. While a response
+/// is being written, throughput is measured over `window`; a client
+/// draining slower than `min_bps` gets the connection closed instead of
+/// pinning it indefinitely. The window restarts after each flush, so
+/// idle keep-alive time doesn't count. Only used when
+/// `server.min_client_read_bps` is set.
+pub struct SlowReadGuard<T> {
+    inner: T,
+    min_bps: u64,
+    window: Duration,
+    window_start: Option<Instant>,
+    written: u64,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> SlowReadGuard<T> {
+    pub fn new(inner: T, min_bps: u64, window: Duration) -> Self {
+        Self {
+            inner,
+            min_bps,
+            window,
+            window_start: None,
+            written: 0,
+            deadline: None,
+        }
+    }
+
+    /// Called once the window has elapsed: error if the client drained
+    /// too slowly, otherwise start a new window.
+    fn check_window(&mut self, now: Instant) -> io::Result<()> {
+        let start = *self.window_start.get_or_insert(now);
+        let elapsed = now.duration_since(start);
+        if elapsed < self.window {
+            return Ok(());
+        }
+
+        let bps = self.written as f64 / elapsed.as_secs_f64();
+        if bps < self.min_bps as f64 {
+            warn!(
+                bytes_per_sec = bps as u64,
+                min_bps = self.min_bps,
+                "Client reading too slowly, closing"
+            );
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "client read too slowly"));
+        }
+        self.window_start = Some(now);
+        self.written = 0;
+        self.deadline = None;
+        Ok(())
+    }
+}
+
+impl<T: AsyncWrite + Unpin> SlowReadGuard<T> {
+    /// Run one write on the inner stream, counting what it wrote.
+    fn poll_measured(
+        &mut self,
+        cx: &mut Context<'_>,
+        write: impl FnOnce(Pin<&mut T>, &mut Context<'_>) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<usize>> {
+        let now = Instant::now();
+        let start = *self.window_start.get_or_insert(now);
+
+        match write(Pin::new(&mut self.inner), cx) {
+            Poll::Ready(Ok(n)) => {
+                self.written += n as u64;
+                self.check_window(Instant::now())?;
+                Poll::Ready(Ok(n))
+            }
+            Poll::Pending => {
+                // A stalled client never wakes us; the timer makes sure
+                // the window still gets checked.
+                let window = self.window;
+                let deadline = self
+                    .deadline
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(start + window)));
+                if deadline.as_mut().poll(cx).is_ready() {
+                    self.check_window(Instant::now())?;
+                    cx.waker().wake_by_ref();
+                }
+                Poll::Pending
+            }
+            ready => ready,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for SlowReadGuard<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for SlowReadGuard<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_measured(cx, |inner, cx| inner.poll_write(cx, buf))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_measured(cx, |inner, cx| inner.poll_write_vectored(cx, bufs))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let flushed = Pin::new(&mut self.inner).poll_flush(cx);
+        if let Poll::Ready(Ok(())) = flushed {
+            self.window_start = None;
+            self.written = 0;
+            self.deadline = None;
+        }
+        flushed
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Upstream connector that enables TCP Fast Open on each connection.
+/// Used instead of HttpConnector when `proxy.upstream_tcp_fast_open` is
+/// set, since HttpConnector has no hook for socket options before
+/// connect.
+#[derive(Clone)]
+pub struct FastOpenConnector {
+    connect_timeout: Option<Duration>,
+}
+
+impl FastOpenConnector {
+    pub fn new(connect_timeout: Option<Duration>) -> Self {
+        Self { connect_timeout }
+    }
+}
+
+impl tower_service::Service<Uri> for FastOpenConnector {
+    type Response = TokioIo<TcpStream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connect_timeout = self.connect_timeout;
+        Box::pin(async move { connect(uri, connect_timeout).await.map(TokioIo::new) })
+    }
+}
+
+async fn connect(uri: Uri, connect_timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let host = uri
+        .host()
+        .map(|h| h.trim_start_matches('[').trim_end_matches(']'))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "upstream URI has no host"))?;
+    let port = uri.port_u16().unwrap_or(80);
+
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "upstream host did not resolve");
+    for addr in tokio::net::lookup_host((host, port)).await? {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        set_fast_open(&socket, FastOpen::Connect);
+
+        let attempt = match connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, socket.connect(addr))
+                .await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))),
+            None => socket.connect(addr).await,
+        };
+        match attempt {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+enum FastOpen {
+    Listener,
+    Connect,
+}
+
+/// Whether this platform can enable TCP Fast Open. Elsewhere the options
+/// are ignored; callers warn about that once at startup.
+pub fn fast_open_supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+/// Fast Open is an optimization: if the kernel refuses it the socket is
+/// used as is, with a warning logged once per mode.
+#[cfg(target_os = "linux")]
+fn set_fast_open(socket: &TcpSocket, mode: FastOpen) {
+    use std::os::fd::AsRawFd;
+    use std::sync::Once;
+
+    static LISTENER_WARNING: Once = Once::new();
+    static CONNECT_WARNING: Once = Once::new();
+
+    // socket2 has no setter for these options, so they are set directly.
+    let (option, value, warning) = match mode {
+        FastOpen::Listener => (libc::TCP_FASTOPEN, FAST_OPEN_QUEUE, &LISTENER_WARNING),
+        FastOpen::Connect => (libc::TCP_FASTOPEN_CONNECT, 1, &CONNECT_WARNING),
+    };
+    // SAFETY: the fd is owned by `socket` and open for the whole call, and
+    // the option value points to an i32 that outlives it, with its size
+    // passed as the length.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            option,
+            &value as *const i32 as *const libc::c_void,
+            std::mem::size_of::<i32>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        let error = io::Error::last_os_error();
+        warning.call_once(|| warn!(error = %error, "TCP Fast Open not enabled"));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fast_open(_socket: &TcpSocket, _mode: FastOpen) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tower_service::Service;
+
+    #[tokio::test]
+    async fn fast_open_listener_and_connector_exchange_data() {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let uri: Uri = format!("http://{addr}/").parse().unwrap();
+        let mut connector = FastOpenConnector::new(Some(Duration::from_secs(1)));
+        let mut stream = connector.call(uri).await.unwrap().into_inner();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_reader_is_disconnected() {
+        let (client, _peer) = tokio::io::duplex(64);
+        let mut guarded = SlowReadGuard::new(client, 1000, Duration::from_secs(1));
+
+        let err = guarded.write_all(&[0u8; 4096]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fast_reader_is_served() {
+        let (client, mut peer) = tokio::io::duplex(64);
+        let mut guarded = SlowReadGuard::new(client, 1000, Duration::from_secs(1));
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            peer.read_to_end(&mut received).await.unwrap();
+            received.len()
+        });
+
+        guarded.write_all(&[0u8; 4096]).await.unwrap();
+        guarded.shutdown().await.unwrap();
+        drop(guarded);
+        assert_eq!(reader.await.unwrap(), 4096);
+    }
+
+    #[test]
+    fn vectored_writes_follow_the_inner_stream() {
+        let window = Duration::from_secs(1);
+        assert!(SlowReadGuard::new(Vec::<u8>::new(), 1, window).is_write_vectored());
+        assert!(!SlowReadGuard::new(tokio::io::sink(), 1, window).is_write_vectored());
+    }
+}